@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use magnus::RHash;
+use tokio::sync::Semaphore;
+use tokio::time::Duration;
+
+use super::auth::Credentials;
+use super::cache::{cache_key, CachedResponse, ResponseCache};
+use super::cancellation::CancellationToken;
+use super::client::Client;
+use super::compression::Encoding;
+use super::endpoint::{Endpoint, Endpoints};
+use super::options::{BatchOptions, ClientOptions};
+use super::request::{join_url, Request};
+use super::response::{ErrorKind, Outcome, Response};
+use super::retry::{parse_retry_after, RetryPolicy};
+use super::transport::is_certificate_error;
+
+// Shared by every call to the module functions so connections get reused.
+// Tagged with the pid that built it, forked children (e.g. puma workers) build their own
+static DEFAULT_CLIENT: Mutex<Option<(u32, Arc<Client>)>> = Mutex::new(None);
+
+/// The default client, unless the options change how the client is built.
+/// The batch takes care of base_url and request_timeout itself,
+/// anything else needs a client of its own
+pub fn client_for(rb_options: Option<RHash>) -> Result<Arc<Client>, magnus::Error> {
+    let mut client_options = ClientOptions::from_rhash(rb_options)?;
+    client_options.base_url = None;
+    client_options.request_timeout = None;
+
+    if client_options == ClientOptions::default() {
+        default_client()
+    } else {
+        Ok(Arc::new(Client::new(client_options)?))
+    }
+}
+
+fn default_client() -> Result<Arc<Client>, magnus::Error> {
+    let mut default_client = DEFAULT_CLIENT
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let pid = std::process::id();
+
+    if let Some((client_pid, client)) = default_client.as_ref() {
+        if *client_pid == pid {
+            return Ok(Arc::clone(client));
+        }
+    }
+
+    // built before a fork, dropping it would wait on runtime threads
+    // that only exist in the parent so leak it instead
+    if let Some(stale_client) = default_client.take() {
+        std::mem::forget(stale_client);
+    }
+
+    let client = Arc::new(Client::new(ClientOptions::default())?);
+    *default_client = Some((pid, Arc::clone(&client)));
+    Ok(client)
+}
+
+/// Execute API calls async with reqwest
+/// Never fails as a whole, requests that fail come back as error responses
+pub async fn batch_send_api_requests(
+    client: reqwest::Client,
+    endpoints: Arc<Endpoints>,
+    credentials: Arc<Credentials>,
+    cache: &ResponseCache,
+    requests: Vec<Request>,
+    options: &BatchOptions,
+) -> Vec<Response> {
+    let mut responses: Vec<Response> = Vec::with_capacity(requests.len());
+    stream_api_requests(
+        client,
+        endpoints,
+        credentials,
+        cache,
+        requests,
+        options,
+        |api_response| responses.push(api_response),
+    )
+    .await;
+
+    // order results in the same order they came in
+    responses.sort_by_key(|r| r.sort_key);
+    responses
+}
+
+/// Execute API calls async with reqwest, handing each response
+/// to `on_response` as soon as it finishes rather than waiting on the whole batch.
+/// Every request gets exactly one response, unfinished ones time out at the batch deadline
+pub async fn stream_api_requests(
+    client: reqwest::Client,
+    endpoints: Arc<Endpoints>,
+    credentials: Arc<Credentials>,
+    cache: &ResponseCache,
+    requests: Vec<Request>,
+    options: &BatchOptions,
+    mut on_response: impl FnMut(Response),
+) {
+    let mut set = tokio::task::JoinSet::new();
+
+    let client: Arc<reqwest::Client> = Arc::new(client);
+    let deadline = options
+        .batch_timeout
+        .map(|batch_timeout| tokio::time::Instant::now() + batch_timeout);
+
+    // every task is spawned straight away but has to hold a permit while its
+    // request is in flight, so anything over the limit queues on the semaphore
+    let max_concurrency = options.max_concurrency.unwrap_or(Semaphore::MAX_PERMITS);
+    let semaphore: Arc<Semaphore> = Arc::new(Semaphore::new(max_concurrency));
+    let options: Arc<BatchOptions> = Arc::new(options.clone());
+
+    // tasks only hand back their task id, so keep track of which request
+    // each task was sending, whatever is left at the deadline never finished
+    let mut sort_keys: HashMap<tokio::task::Id, i32> = HashMap::with_capacity(requests.len());
+
+    // with a cache, identical requests only go upstream once. The first of them
+    // answers for its duplicates when it finishes, and is cached under its key
+    let mut first_with_key: HashMap<String, i32> = HashMap::new();
+    let mut cache_keys: HashMap<i32, String> = HashMap::new();
+    let mut duplicates: HashMap<i32, Vec<i32>> = HashMap::new();
+    let auth = credentials.current();
+
+    for (sort_key, r) in requests.into_iter().enumerate() {
+        let sort_key = sort_key as i32;
+
+        if cache.is_enabled() {
+            let key = cache_key(&r, auth.as_ref());
+            if let Some(cached) = cache.get(&key).await {
+                on_response(cached_response(sort_key, cached));
+                continue;
+            }
+            if let Some(first) = first_with_key.get(&key) {
+                duplicates.entry(*first).or_default().push(sort_key);
+                continue;
+            }
+            first_with_key.insert(key.clone(), sort_key);
+            cache_keys.insert(sort_key, key);
+        }
+
+        let client: Arc<reqwest::Client> = Arc::clone(&client);
+        let endpoints: Arc<Endpoints> = Arc::clone(&endpoints);
+        let credentials: Arc<Credentials> = Arc::clone(&credentials);
+        let semaphore: Arc<Semaphore> = Arc::clone(&semaphore);
+        let options: Arc<BatchOptions> = Arc::clone(&options);
+
+        let abort_handle = set.spawn(async move {
+            send_api_request_with_retries(
+                &client,
+                &endpoints,
+                &credentials,
+                &semaphore,
+                &options,
+                sort_key,
+                &r,
+            )
+            .await
+        });
+        sort_keys.insert(abort_handle.id(), sort_key);
+    }
+
+    // disk writes still going, finished before the batch returns
+    let mut writes = Vec::new();
+    let mut respond = |api_response: Response| {
+        if let Some(key) = cache_keys.remove(&api_response.sort_key) {
+            writes.extend(cache.put(&key, &api_response.outcome));
+        }
+        for sort_key in duplicates
+            .remove(&api_response.sort_key)
+            .unwrap_or_default()
+        {
+            on_response(Response {
+                sort_key,
+                cache_hit: true,
+                ..api_response.clone()
+            });
+        }
+        on_response(api_response);
+    };
+
+    // what unfinished requests come back as if the batch stops early
+    let mut unfinished = (
+        ErrorKind::Timeout,
+        "batch timeout elapsed before the request finished",
+    );
+
+    // Run the joinset to completion, they return in the order they finish.
+    // Breaking out early drops the set at the end, aborting the unfinished tasks
+    loop {
+        let res = tokio::select! {
+            // checked first so a cancelled batch stops even while responses keep finishing
+            biased;
+            _ = cancelled(options.cancellation_token.as_ref()) => {
+                unfinished = (ErrorKind::Cancelled, "batch was cancelled before the request finished");
+                break;
+            }
+            _ = reached(deadline) => break,
+            res = set.join_next_with_id() => res,
+        };
+
+        let api_response = match res {
+            Some(Ok((id, api_response))) => {
+                sort_keys.remove(&id);
+                api_response
+            }
+            Some(Err(join_error)) => {
+                let sort_key = sort_keys.remove(&join_error.id()).unwrap_or_default();
+                Response::error(sort_key, ErrorKind::Panic, panic_message(join_error))
+            }
+            None => break,
+        };
+        respond(api_response);
+    }
+
+    // partial results, flag everything that didn't make the deadline or was cancelled
+    let (error_kind, message) = unfinished;
+    for sort_key in sort_keys.into_values() {
+        respond(Response::error(sort_key, error_kind, String::from(message)));
+    }
+
+    for write in writes {
+        let _ = write.await;
+    }
+}
+
+// never finishes without a token, so the batch runs as normal
+async fn cancelled(cancellation_token: Option<&CancellationToken>) {
+    match cancellation_token {
+        Some(cancellation_token) => cancellation_token.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+// never finishes without a batch timeout
+async fn reached(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sends a request until it succeeds, fails for good or runs out of attempts.
+/// The response has the last outcome along with how many attempts it took,
+/// which endpoint the last attempt went to, how long it took and how long it was queued
+async fn send_api_request_with_retries(
+    client: &reqwest::Client,
+    endpoints: &Endpoints,
+    credentials: &Credentials,
+    semaphore: &Semaphore,
+    options: &BatchOptions,
+    sort_key: i32,
+    r: &Request,
+) -> Response {
+    let retry: &RetryPolicy = &options.retry;
+    let mut attempt: u32 = 1;
+    let mut queue_wait = Duration::ZERO;
+    // compressed once up front rather than on every attempt
+    let compressed = options
+        .compression
+        .as_ref()
+        .filter(|_| !r.body.is_empty())
+        .and_then(|policy| policy.compress(&r.body).map(|body| (policy.encoding, body)));
+
+    loop {
+        let queued_at = tokio::time::Instant::now();
+
+        // pick the endpoint for every attempt so retries can land on a healthy one,
+        // requests only come without a base url when the client has endpoints to balance
+        let endpoint = match &r.base_url {
+            Some(base_url) => Some(endpoints.fixed(base_url)),
+            None => endpoints.pick(),
+        };
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => {
+                let message = String::from("no endpoint to send the request to");
+                return Response::error(sort_key, ErrorKind::Connect, message);
+            }
+        };
+
+        // waited out before taking a permit or a circuit trial so requests held back by
+        // the endpoint's rate limit don't hold up the rest. Handed back unless it's sent
+        let turn = endpoint.wait_for_turn().await;
+
+        // only held while the request is in flight so backing off frees the slot.
+        // acquire only errors if the semaphore is closed, which we never do
+        let permit = semaphore.acquire().await.unwrap();
+
+        // fail fast rather than wait out the timeouts of an endpoint that's down
+        let admission = match endpoint.admit() {
+            Some(admission) => admission,
+            None => return circuit_open_response(sort_key, &endpoint, attempt - 1),
+        };
+        queue_wait += queued_at.elapsed();
+        if let Some(turn) = turn {
+            turn.spend();
+        }
+
+        // timed from when the request goes out, so queueing isn't counted
+        let started_at = tokio::time::Instant::now();
+        let (outcome, retry_after) = {
+            let _in_flight = endpoint.start_request();
+            let url = join_url(&endpoint.url, &r.path);
+            send_api_request(client, credentials, options, &url, r, compressed.as_ref()).await
+        };
+        let latency = started_at.elapsed();
+        drop(permit);
+        endpoint.record_outcome(&outcome, &endpoints.ejection);
+        drop(admission);
+
+        if attempt >= retry.max_attempts || !retry.is_retryable(&outcome) {
+            return Response {
+                sort_key,
+                outcome,
+                attempts: attempt,
+                endpoint: Some(endpoint.url.clone()),
+                latency: Some(latency),
+                queue_wait: Some(queue_wait),
+                cache_hit: false,
+            };
+        }
+
+        tokio::time::sleep(retry.delay(attempt, retry_after)).await;
+        attempt += 1;
+    }
+}
+
+/// A request turned away by the endpoint's circuit breaker, without being sent
+fn circuit_open_response(sort_key: i32, endpoint: &Endpoint, attempts: u32) -> Response {
+    let retry_in = endpoint
+        .circuit
+        .as_ref()
+        .and_then(|circuit| circuit.retry_in());
+    let message = match retry_in {
+        Some(retry_in) => format!(
+            "circuit breaker for {} is open, trying again in {:.1}s",
+            endpoint.url,
+            retry_in.as_secs_f64()
+        ),
+        None => format!(
+            "circuit breaker for {} is half open and waiting on its trial requests",
+            endpoint.url
+        ),
+    };
+
+    Response {
+        attempts,
+        endpoint: Some(endpoint.url.clone()),
+        ..Response::error(sort_key, ErrorKind::CircuitOpen, message)
+    }
+}
+
+/// A response served from the cache without sending the request
+fn cached_response(sort_key: i32, cached: CachedResponse) -> Response {
+    Response {
+        sort_key,
+        outcome: cached.into_outcome(),
+        attempts: 0,
+        endpoint: None,
+        latency: None,
+        queue_wait: None,
+        cache_hit: true,
+    }
+}
+
+/// Sends a single request, turning any failure along the way into an error outcome.
+/// Also hands back the Retry-After header if the server sent one
+async fn send_api_request(
+    client: &reqwest::Client,
+    credentials: &Credentials,
+    options: &BatchOptions,
+    url: &str,
+    r: &Request,
+    compressed: Option<&(Encoding, Vec<u8>)>,
+) -> (Outcome, Option<Duration>) {
+    let mut request_builder = client.request(r.method.clone(), url);
+    if !r.query.is_empty() {
+        request_builder = request_builder.query(&r.query);
+    }
+    // bodyless requests, like most GETs, go without a content type
+    if !r.body.is_empty() {
+        request_builder = request_builder.header("Content-Type", "application/json");
+    }
+
+    // read on every attempt so a refreshed token is used from then on
+    let auth = credentials.current();
+    if let Some(auth) = auth.as_ref() {
+        request_builder = auth.apply(request_builder);
+    }
+    // after auth so a request's own Authorization header wins
+    request_builder = request_builder.headers(r.headers.clone());
+
+    request_builder = match compressed {
+        Some((encoding, body)) => request_builder
+            .header("Content-Encoding", encoding.as_str())
+            .body(body.clone()),
+        None if r.body.is_empty() => request_builder,
+        None => request_builder.body(r.body.clone()),
+    };
+
+    if let Some(request_timeout) = options.request_timeout {
+        request_builder = request_builder.timeout(request_timeout);
+    }
+
+    let reqwest_response = match request_builder.send().await {
+        Ok(reqwest_response) => reqwest_response,
+        Err(err) => {
+            let outcome = Outcome::Error {
+                error_kind: send_error_kind(&err),
+                message: credentials.redact(err.to_string(), auth.as_ref(), &r.headers),
+            };
+            return (outcome, None);
+        }
+    };
+
+    let http_status_code = reqwest_response.status().as_u16();
+    let retry_after = parse_retry_after(reqwest_response.headers());
+    let headers: Vec<(String, String)> = options
+        .response_headers
+        .iter()
+        .filter_map(|name| {
+            // skip values that aren't valid strings rather than failing the request
+            let value = reqwest_response.headers().get(name)?.to_str().ok()?;
+            Some((name.clone(), value.to_string()))
+        })
+        .collect();
+    // consumes self so do it after we get the status code
+    let outcome = match reqwest_response.text().await {
+        Ok(body) => Outcome::Http {
+            http_status_code,
+            body,
+            headers,
+        },
+        Err(err) => Outcome::Error {
+            error_kind: body_error_kind(&err),
+            message: credentials.redact(err.to_string(), auth.as_ref(), &r.headers),
+        },
+    };
+    (outcome, retry_after)
+}
+
+// reqwest folds dns, refused connections and the like into connect errors,
+// certificate problems won't go away by retrying so they get their own kind.
+// Anything else, like too many redirects, is down to the request and isn't retried
+fn send_error_kind(err: &reqwest::Error) -> ErrorKind {
+    if err.is_timeout() {
+        ErrorKind::Timeout
+    } else if is_certificate_error(err) {
+        ErrorKind::Certificate
+    } else if err.is_connect() || err.is_request() {
+        ErrorKind::Connect
+    } else if err.is_body() || err.is_decode() {
+        ErrorKind::BodyRead
+    } else {
+        ErrorKind::Request
+    }
+}
+
+// the request timeout also covers reading the body
+fn body_error_kind(err: &reqwest::Error) -> ErrorKind {
+    if err.is_timeout() {
+        ErrorKind::Timeout
+    } else {
+        ErrorKind::BodyRead
+    }
+}
+
+fn panic_message(join_error: tokio::task::JoinError) -> String {
+    if !join_error.is_panic() {
+        return join_error.to_string();
+    }
+
+    let payload = join_error.into_panic();
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("request task panicked")
+    }
+}
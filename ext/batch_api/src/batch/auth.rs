@@ -0,0 +1,163 @@
+// Headers and auth for instances behind an auth proxy.
+// Secrets are marked sensitive so reqwest won't print them,
+// and scrubbed from error messages before they get back to ruby
+
+use std::sync::{PoisonError, RwLock};
+
+use magnus::RHash;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::options::fetch;
+
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Clone, PartialEq)]
+pub enum Auth {
+    Bearer(String),
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+}
+
+// written by hand so options and requests can be debug printed without leaking anything
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Auth::Bearer(_) => write!(f, "Bearer({})", REDACTED),
+            Auth::Basic { username, .. } => write!(f, "Basic({}, {})", username, REDACTED),
+        }
+    }
+}
+
+impl Auth {
+    /// `{ bearer: 'token' }` or `{ username: 'user', password: 'secret' }`
+    pub fn from_rhash(rb_hash: RHash) -> Result<Self, magnus::Error> {
+        let bearer = fetch::<String>(rb_hash, "bearer")?;
+        let username = fetch::<String>(rb_hash, "username")?;
+
+        match (bearer, username) {
+            (Some(token), None) => Ok(Auth::Bearer(token)),
+            (None, Some(username)) => Ok(Auth::Basic {
+                username,
+                password: fetch::<String>(rb_hash, "password")?,
+            }),
+            _ => {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "auth needs either a bearer token or a username",
+                );
+                Err(rb_error)
+            }
+        }
+    }
+
+    pub fn apply(&self, request_builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self {
+            Auth::Bearer(token) => request_builder.bearer_auth(token),
+            Auth::Basic { username, password } => {
+                request_builder.basic_auth(username, password.as_ref())
+            }
+        }
+    }
+
+    fn secret(&self) -> Option<&str> {
+        match self {
+            Auth::Bearer(token) => Some(token),
+            Auth::Basic { password, .. } => password.as_deref(),
+        }
+    }
+}
+
+/// Auth shared by every batch a client sends, swapped out in place
+/// when a token is refreshed so retries and later batches pick it up
+#[derive(Debug, Default)]
+pub struct Credentials {
+    auth: RwLock<Option<Auth>>,
+    // values of the client's sensitive headers, and its proxy password
+    secrets: Vec<String>,
+}
+
+impl Credentials {
+    pub fn new(auth: Option<Auth>, headers: &HeaderMap) -> Self {
+        Credentials {
+            auth: RwLock::new(auth),
+            secrets: sensitive_values(headers),
+        }
+    }
+
+    pub fn add_secret(&mut self, secret: String) {
+        self.secrets.push(secret);
+    }
+
+    pub fn current(&self) -> Option<Auth> {
+        self.auth
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn set(&self, auth: Option<Auth>) {
+        *self.auth.write().unwrap_or_else(PoisonError::into_inner) = auth;
+    }
+
+    /// Replaces any secret the request was sent with, e.g. when an error message includes the url
+    pub fn redact(&self, message: String, auth: Option<&Auth>, headers: &HeaderMap) -> String {
+        let request_secrets = sensitive_values(headers);
+        let secrets = self
+            .secrets
+            .iter()
+            .chain(request_secrets.iter())
+            .map(String::as_str)
+            .chain(auth.and_then(Auth::secret));
+
+        let mut message = message;
+        for secret in secrets.filter(|secret| !secret.is_empty()) {
+            message = message.replace(secret, REDACTED);
+        }
+        message
+    }
+}
+
+/// Validates header names and values from ruby, marking credentials as sensitive.
+/// Errors name the header but never include its value
+pub fn header_map(rb_hash: RHash) -> Result<HeaderMap, magnus::Error> {
+    let mut headers = HeaderMap::new();
+
+    for (name, value) in rb_hash.to_hash_map::<String, String>()? {
+        let header_name = HeaderName::from_bytes(name.as_bytes()).map_err(|err| {
+            magnus::Error::new(
+                magnus::exception::arg_error(),
+                format!("invalid header {}: {}", name, err),
+            )
+        })?;
+        let mut header_value = HeaderValue::from_str(&value).map_err(|_| {
+            magnus::Error::new(
+                magnus::exception::arg_error(),
+                format!("invalid value for header {}", name),
+            )
+        })?;
+
+        header_value.set_sensitive(is_sensitive(&header_name));
+        headers.insert(header_name, header_value);
+    }
+    Ok(headers)
+}
+
+// credentials under their usual names, plus anything that looks like an api key or token
+fn is_sensitive(name: &HeaderName) -> bool {
+    let name = name.as_str();
+    matches!(name, "authorization" | "proxy-authorization" | "cookie")
+        || ["token", "secret", "key", "password"]
+            .iter()
+            .any(|word| name.contains(word))
+}
+
+fn sensitive_values(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .values()
+        .filter(|value| value.is_sensitive())
+        .filter_map(|value| value.to_str().ok())
+        .map(String::from)
+        .collect()
+}
@@ -0,0 +1,231 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use magnus::RHash;
+
+use super::CachedResponse;
+use crate::options::{fetch, fetch_duration};
+
+// sweeping the directory means listing every file, so don't do it on every write
+const SWEEP_EVERY: Duration = Duration::from_secs(60);
+// temporary files older than this were left by a writer that died part way through
+const ABANDONED_AFTER: Duration = Duration::from_secs(600);
+
+/// Where a client keeps responses on disk and when they're thrown away
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorePolicy {
+    pub path: PathBuf,
+    // None keeps responses however old they are
+    pub max_age: Option<Duration>,
+    // total size of the compressed files, oldest go first past it
+    pub max_bytes: Option<u64>,
+}
+
+impl StorePolicy {
+    pub fn from_rhash(rb_hash: RHash) -> Result<Self, magnus::Error> {
+        let path = match fetch::<String>(rb_hash, "path")? {
+            Some(path) => PathBuf::from(path),
+            None => {
+                let rb_error =
+                    magnus::Error::new(magnus::exception::arg_error(), "store needs a path");
+                return Err(rb_error);
+            }
+        };
+
+        let max_bytes = match fetch::<u64>(rb_hash, "max_bytes")? {
+            Some(0) => {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "max_bytes must be greater than 0",
+                );
+                return Err(rb_error);
+            }
+            max_bytes => max_bytes,
+        };
+
+        Ok(StorePolicy {
+            path,
+            max_age: fetch_duration(rb_hash, "max_age")?,
+            max_bytes,
+        })
+    }
+}
+
+/// A directory of gzipped responses named by their cache key, so they
+/// survive restarts and can be shared by every process pointed at it.
+/// Unreadable or corrupt files are treated as misses rather than errors
+#[derive(Debug)]
+pub struct DiskStore {
+    policy: StorePolicy,
+    last_swept: Mutex<Instant>,
+    // makes temporary file names unique between threads
+    writes: AtomicU64,
+}
+
+impl DiskStore {
+    pub fn open(policy: StorePolicy) -> Result<Self, magnus::Error> {
+        fs::create_dir_all(&policy.path).map_err(|err| {
+            magnus::Error::new(
+                magnus::exception::io_error(),
+                format!("can't open store {}: {}", policy.path.display(), err),
+            )
+        })?;
+
+        let store = DiskStore {
+            policy,
+            last_swept: Mutex::new(Instant::now()),
+            writes: AtomicU64::new(0),
+        };
+        store.sweep();
+        Ok(store)
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedResponse> {
+        let path = self.path_for(key);
+        let file = File::open(&path).ok()?;
+
+        if self.is_expired(&file.metadata().ok()?) {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+
+        match serde_json::from_reader(GzDecoder::new(BufReader::new(file))) {
+            Ok(response) => Some(response),
+            Err(_) => {
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    /// Writes to a temporary file first then renames it into place,
+    /// so readers never see half written responses
+    pub fn put(&self, key: &str, response: &CachedResponse) {
+        let path = self.path_for(key);
+        if let Some(subdirectory) = path.parent() {
+            let _ = fs::create_dir_all(subdirectory);
+        }
+        let temporary_path = path.with_extension(format!(
+            "{}.{}.tmp",
+            std::process::id(),
+            self.writes.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        let written = write_gzipped(&temporary_path, response)
+            .and_then(|_| fs::rename(&temporary_path, &path));
+        if written.is_err() {
+            let _ = fs::remove_file(&temporary_path);
+        }
+    }
+
+    /// Whether it's been long enough since the last sweep for another, counting this one as started
+    pub fn is_sweep_due(&self) -> bool {
+        let mut last_swept = self
+            .last_swept
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if last_swept.elapsed() < SWEEP_EVERY {
+            return false;
+        }
+        *last_swept = Instant::now();
+        true
+    }
+
+    /// Removes responses past their max age, then the oldest ones
+    /// until the store is back under its max size
+    pub fn sweep(&self) {
+        let mut files: Vec<(PathBuf, u64, SystemTime)> = Vec::new();
+        let subdirectories = match fs::read_dir(&self.policy.path) {
+            Ok(subdirectories) => subdirectories,
+            Err(_) => return,
+        };
+
+        for subdirectory in subdirectories.flatten() {
+            let entries = match fs::read_dir(subdirectory.path()) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let metadata = match entry.metadata() {
+                    Ok(metadata) if metadata.is_file() => metadata,
+                    _ => continue,
+                };
+
+                // another writer's response on its way in, unless it was left behind long ago
+                if entry
+                    .path()
+                    .extension()
+                    .is_some_and(|extension| extension == "tmp")
+                {
+                    if age(&metadata).is_some_and(|age| age >= ABANDONED_AFTER) {
+                        let _ = fs::remove_file(entry.path());
+                    }
+                    continue;
+                }
+
+                if self.is_expired(&metadata) {
+                    let _ = fs::remove_file(entry.path());
+                    continue;
+                }
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                files.push((entry.path(), metadata.len(), modified));
+            }
+        }
+
+        let max_bytes = match self.policy.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return,
+        };
+        let mut total_bytes: u64 = files.iter().map(|(_, size, _)| size).sum();
+
+        // oldest first
+        files.sort_by_key(|(_, _, modified)| *modified);
+        for (path, size, _) in files {
+            if total_bytes <= max_bytes {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                total_bytes -= size;
+            }
+        }
+    }
+
+    fn is_expired(&self, metadata: &fs::Metadata) -> bool {
+        let max_age = match self.policy.max_age {
+            Some(max_age) => max_age,
+            None => return false,
+        };
+
+        age(metadata).is_some_and(|age| age >= max_age)
+    }
+
+    // spread over subdirectories by the first byte of the key
+    // so no one directory ends up with every file in it
+    fn path_for(&self, key: &str) -> PathBuf {
+        self.policy
+            .path
+            .join(&key[..2.min(key.len())])
+            .join(format!("{}.json.gz", key))
+    }
+}
+
+// how long ago the file was last written
+fn age(metadata: &fs::Metadata) -> Option<Duration> {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+}
+
+fn write_gzipped(path: &Path, response: &CachedResponse) -> std::io::Result<()> {
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(path)?), Compression::default());
+    serde_json::to_writer(&mut encoder, response)?;
+    encoder.finish()?.into_inner()?.sync_all()
+}
@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use magnus::RHash;
+
+use super::CachedResponse;
+use crate::options::{fetch, fetch_duration};
+
+/// How many responses, or matrices, a client keeps in memory and for how long
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachePolicy {
+    pub max_entries: usize,
+    // None keeps entries until they're pushed out by newer ones
+    pub ttl: Option<Duration>,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        CachePolicy {
+            max_entries: 1000,
+            ttl: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+impl CachePolicy {
+    pub fn from_rhash(rb_hash: RHash) -> Result<Self, magnus::Error> {
+        Self::from_rhash_or(rb_hash, Self::default())
+    }
+
+    /// Like from_rhash, starting from other defaults for the keys that aren't given
+    pub fn from_rhash_or(rb_hash: RHash, defaults: Self) -> Result<Self, magnus::Error> {
+        let mut policy = defaults;
+
+        if let Some(max_entries) = fetch::<usize>(rb_hash, "max_entries")? {
+            if max_entries == 0 {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "max_entries must be greater than 0",
+                );
+                return Err(rb_error);
+            }
+            policy.max_entries = max_entries;
+        }
+
+        // ttl: false keeps entries forever, fetching as a bool only tells us it's truthy
+        match fetch::<bool>(rb_hash, "ttl")? {
+            Some(false) => policy.ttl = None,
+            Some(true) => policy.ttl = fetch_duration(rb_hash, "ttl")?,
+            None => {}
+        }
+
+        Ok(policy)
+    }
+}
+
+/// A least recently used cache, safe to share between batches on different threads.
+/// Holds responses unless told otherwise
+#[derive(Debug)]
+pub struct MemoryCache<T = CachedResponse> {
+    policy: CachePolicy,
+    lru: Mutex<Lru<T>>,
+}
+
+#[derive(Debug)]
+struct Lru<T> {
+    entries: HashMap<String, Entry<T>>,
+    // keys by when they were last used, oldest first
+    recency: BTreeMap<u64, String>,
+    // bumped on every use, orders the recency map
+    clock: u64,
+}
+
+// derived Default would want T: Default
+impl<T> Default for Lru<T> {
+    fn default() -> Self {
+        Lru {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    value: T,
+    stored_at: Instant,
+    used_at: u64,
+}
+
+impl<T: Clone> MemoryCache<T> {
+    pub fn new(policy: CachePolicy) -> Self {
+        MemoryCache {
+            policy,
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<T> {
+        let mut lru = self.lru();
+        let entry = lru.entries.get(key)?;
+
+        if let Some(ttl) = self.policy.ttl {
+            if entry.stored_at.elapsed() >= ttl {
+                lru.remove(key);
+                return None;
+            }
+        }
+
+        let used_at = lru.tick();
+        let entry = lru.entries.get_mut(key)?;
+        let last_used_at = std::mem::replace(&mut entry.used_at, used_at);
+        let value = entry.value.clone();
+
+        lru.recency.remove(&last_used_at);
+        lru.recency.insert(used_at, key.to_string());
+        Some(value)
+    }
+
+    pub fn put(&self, key: &str, value: T) {
+        let mut lru = self.lru();
+        lru.remove(key);
+
+        let used_at = lru.tick();
+        lru.entries.insert(
+            key.to_string(),
+            Entry {
+                value,
+                stored_at: Instant::now(),
+                used_at,
+            },
+        );
+        lru.recency.insert(used_at, key.to_string());
+
+        // push out the least recently used until we're back under the limit
+        while lru.entries.len() > self.policy.max_entries {
+            match lru.recency.pop_first() {
+                Some((_, oldest)) => {
+                    lru.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn lru(&self) -> std::sync::MutexGuard<'_, Lru<T>> {
+        self.lru.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> Lru<T> {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used_at);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+// Vroom responses kept so repeat problems don't have to be solved again.
+// Keyed by a hash of the request, with the json body canonicalized so
+// key order and whitespace don't stop identical problems from matching
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::task::JoinHandle;
+
+use super::auth::Auth;
+use super::request::Request;
+use super::response::Outcome;
+
+mod disk;
+mod memory;
+
+pub use disk::{DiskStore, StorePolicy};
+pub use memory::{CachePolicy, MemoryCache};
+
+/// What gets kept of a successful response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub http_status_code: u16,
+    pub body: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl CachedResponse {
+    pub fn into_outcome(self) -> Outcome {
+        Outcome::Http {
+            http_status_code: self.http_status_code,
+            body: self.body,
+            headers: self.headers,
+        }
+    }
+}
+
+/// Every cache a client looks in before sending a request, does nothing if none are set up.
+/// Memory is checked first, then the disk store, whose hits are kept in memory for next time.
+/// The disk store is only touched from blocking threads, so reads, fsyncs and sweeps
+/// don't hold up the requests in flight on the runtime
+#[derive(Debug, Default)]
+pub struct ResponseCache {
+    memory: Option<MemoryCache>,
+    disk: Option<Arc<DiskStore>>,
+}
+
+impl ResponseCache {
+    pub fn new(memory: Option<MemoryCache>, disk: Option<DiskStore>) -> Self {
+        ResponseCache {
+            memory,
+            disk: disk.map(Arc::new),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.memory.is_some() || self.disk.is_some()
+    }
+
+    pub async fn get(&self, key: &str) -> Option<CachedResponse> {
+        if let Some(cached) = self.memory.as_ref().and_then(|memory| memory.get(key)) {
+            return Some(cached);
+        }
+
+        let disk = Arc::clone(self.disk.as_ref()?);
+        let disk_key = key.to_string();
+        let cached = tokio::task::spawn_blocking(move || disk.get(&disk_key))
+            .await
+            .ok()
+            .flatten()?;
+        if let Some(memory) = &self.memory {
+            memory.put(key, cached.clone());
+        }
+        Some(cached)
+    }
+
+    /// Only successful responses are kept, errors are worth asking again.
+    /// Gives back the disk write, which the batch waits on before it returns
+    /// so the next batch can find it. Sweeping the store runs in the background
+    pub fn put(&self, key: &str, outcome: &Outcome) -> Option<JoinHandle<()>> {
+        let cached = match outcome {
+            Outcome::Http {
+                http_status_code,
+                body,
+                headers,
+            } if (200..300).contains(http_status_code) => CachedResponse {
+                http_status_code: *http_status_code,
+                body: body.clone(),
+                headers: headers.clone(),
+            },
+            _ => return None,
+        };
+
+        if let Some(memory) = &self.memory {
+            memory.put(key, cached.clone());
+        }
+
+        let disk = Arc::clone(self.disk.as_ref()?);
+        let key = key.to_string();
+        Some(tokio::task::spawn_blocking(move || {
+            disk.put(&key, &cached);
+            if disk.is_sweep_due() {
+                tokio::task::spawn_blocking(move || disk.sweep());
+            }
+        }))
+    }
+}
+
+/// Sha256 of where the request goes, the auth and headers it's sent with and its canonical json body,
+/// hex encoded. Balanced requests leave out the base url since any of the endpoints gives the same answer
+pub fn cache_key(r: &Request, auth: Option<&Auth>) -> String {
+    let mut hasher = Sha256::new();
+    // only for http batches, so vroom's keys stay as they were
+    if r.method != reqwest::Method::POST {
+        hasher.update(r.method.as_str());
+        hasher.update([0]);
+    }
+    hasher.update(r.base_url.as_deref().unwrap_or_default());
+    hasher.update([0]);
+    hasher.update(&r.path);
+    hasher.update([0]);
+    for (name, value) in r.query.iter() {
+        hasher.update(name);
+        hasher.update([0]);
+        hasher.update(value);
+        hasher.update([0]);
+    }
+    // requests sent with other credentials or for another tenant mustn't share answers.
+    // header names are already lowercase, only http batches have any so vroom's keys stay as they were
+    let mut headers: Vec<_> = r.headers.iter().collect();
+    headers.sort_by(|(a_name, a_value), (b_name, b_value)| {
+        (a_name.as_str(), a_value.as_bytes()).cmp(&(b_name.as_str(), b_value.as_bytes()))
+    });
+    for (name, value) in headers {
+        hasher.update(name.as_str());
+        hasher.update([0]);
+        hasher.update(value.as_bytes());
+        hasher.update([0]);
+    }
+    // so answers cached under credentials the client has since swapped aren't served for the new ones
+    match auth {
+        Some(Auth::Bearer(token)) => {
+            hasher.update("bearer");
+            hasher.update([0]);
+            hasher.update(token);
+            hasher.update([0]);
+        }
+        Some(Auth::Basic { username, password }) => {
+            hasher.update("basic");
+            hasher.update([0]);
+            hasher.update(username);
+            hasher.update([0]);
+            hasher.update(password.as_deref().unwrap_or_default());
+            hasher.update([0]);
+        }
+        None => {}
+    }
+    hasher.update(canonical_body(&r.body));
+    hex_digest(hasher)
+}
+
+/// Sha256 of just the canonical json body, hex encoded,
+/// for matching requests whichever instance they were sent to
+pub fn body_key(body: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(canonical_body(body));
+    hex_digest(hasher)
+}
+
+// serde_json's maps are sorted by key, so reserializing puts every object's keys in order.
+// bodies that aren't json are used as they are
+fn canonical_body(body: &str) -> String {
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(json) => json.to_string(),
+        Err(_) => body.to_string(),
+    }
+}
+
+fn hex_digest(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
@@ -0,0 +1,57 @@
+// Lets ruby stop a batch part way through, e.g. from a Timeout.timeout ensure block,
+// a request being cancelled or a Sidekiq shutdown hook, running on another thread
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// `token = BatchApi::Vroom::CancellationToken.new`, passed to batches as
+/// `cancellation_token: token`. Cancelling aborts their requests still in flight,
+/// they come back with the responses that already finished.
+/// Stays cancelled, so use a new token for each unit of work
+#[derive(Debug, Clone)]
+#[magnus::wrap(class = "BatchApi::Vroom::CancellationToken", free_immediately)]
+pub struct CancellationToken {
+    // a watch rather than a Notify so batches starting after cancel still see it
+    cancelled: Arc<watch::Sender<bool>>,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken {
+            cancelled: Arc::new(watch::Sender::new(false)),
+        }
+    }
+}
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.cancelled.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.cancelled.borrow()
+    }
+
+    /// Finishes once the token is cancelled, straight away if it already is
+    pub async fn cancelled(&self) {
+        let mut receiver = self.cancelled.subscribe();
+        // only errors if the sender's dropped, and we're holding it
+        let _ = receiver.wait_for(|cancelled| *cancelled).await;
+    }
+
+    // Functions for our ruby interface
+
+    pub fn rb_new() -> Self {
+        Self::default()
+    }
+
+    /// Safe to call from any thread, and more than once
+    pub fn rb_cancel(&self) {
+        self.cancel();
+    }
+
+    pub fn rb_is_cancelled(&self) -> bool {
+        self.is_cancelled()
+    }
+}
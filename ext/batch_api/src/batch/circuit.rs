@@ -0,0 +1,219 @@
+// Circuit breakers so an instance that's down fails requests straight away
+// instead of every one of them waiting out its timeouts
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use magnus::RHash;
+
+use crate::options::{fetch, fetch_duration};
+
+/// `circuit_breaker: { failure_threshold: 5, reset_timeout: 30, half_open_requests: 1 }`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitBreakerPolicy {
+    // failures in a row that open the circuit
+    pub failure_threshold: u32,
+    // how long it stays open before letting trial requests through
+    pub reset_timeout: Duration,
+    // trial requests let through at once while half open
+    pub half_open_requests: u32,
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        CircuitBreakerPolicy {
+            failure_threshold: 5,
+            reset_timeout: Duration::from_secs(30),
+            half_open_requests: 1,
+        }
+    }
+}
+
+impl CircuitBreakerPolicy {
+    pub fn from_rhash(rb_hash: RHash) -> Result<Self, magnus::Error> {
+        let mut policy = Self::default();
+
+        if let Some(failure_threshold) = fetch::<u32>(rb_hash, "failure_threshold")? {
+            policy.failure_threshold = failure_threshold;
+        }
+        if let Some(reset_timeout) = fetch_duration(rb_hash, "reset_timeout")? {
+            policy.reset_timeout = reset_timeout;
+        }
+        if let Some(half_open_requests) = fetch::<u32>(rb_hash, "half_open_requests")? {
+            policy.half_open_requests = half_open_requests;
+        }
+
+        if policy.failure_threshold == 0 || policy.half_open_requests == 0 {
+            let rb_error = magnus::Error::new(
+                magnus::exception::arg_error(),
+                "circuit_breaker failure_threshold and half_open_requests must be greater than 0",
+            );
+            return Err(rb_error);
+        }
+
+        Ok(policy)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    policy: CircuitBreakerPolicy,
+    circuit: Mutex<Circuit>,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    consecutive_failures: u32,
+    // when it last opened
+    opened_at: Option<Instant>,
+    // trial requests in flight while half open
+    trials: u32,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: CircuitBreakerPolicy) -> Self {
+        CircuitBreaker {
+            policy,
+            circuit: Mutex::new(Circuit {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                trials: 0,
+            }),
+        }
+    }
+
+    /// Lets a request through unless the circuit is open, or half open with its trials taken.
+    /// An open circuit turns half open here once the reset timeout is over
+    pub fn admit(&self) -> Option<Admission<'_>> {
+        let mut circuit = self.circuit();
+
+        if circuit.state == CircuitState::Open && !self.is_cooling_down(&circuit, Instant::now()) {
+            circuit.state = CircuitState::HalfOpen;
+            circuit.trials = 0;
+        }
+
+        match circuit.state {
+            CircuitState::Closed => Some(Admission::default()),
+            CircuitState::Open => None,
+            CircuitState::HalfOpen if circuit.trials < self.policy.half_open_requests => {
+                circuit.trials += 1;
+                Some(Admission {
+                    circuit: Some(self),
+                    trial: true,
+                })
+            }
+            CircuitState::HalfOpen => None,
+        }
+    }
+
+    /// Whether a request would be turned away right now, without taking a trial
+    pub fn is_rejecting(&self, now: Instant) -> bool {
+        let circuit = self.circuit();
+        match circuit.state {
+            CircuitState::Closed => false,
+            CircuitState::Open => self.is_cooling_down(&circuit, now),
+            CircuitState::HalfOpen => circuit.trials >= self.policy.half_open_requests,
+        }
+    }
+
+    /// Failures in a row open a closed circuit, a half open one closes on
+    /// its first success and opens again on its first failure
+    pub fn record(&self, failed: bool) {
+        let mut circuit = self.circuit();
+
+        match (circuit.state, failed) {
+            (CircuitState::Closed, true) => {
+                circuit.consecutive_failures += 1;
+                if circuit.consecutive_failures >= self.policy.failure_threshold {
+                    circuit.state = CircuitState::Open;
+                    circuit.opened_at = Some(Instant::now());
+                }
+            }
+            (CircuitState::HalfOpen, true) => {
+                circuit.state = CircuitState::Open;
+                circuit.opened_at = Some(Instant::now());
+            }
+            (CircuitState::Closed | CircuitState::HalfOpen, false) => {
+                circuit.state = CircuitState::Closed;
+                circuit.consecutive_failures = 0;
+            }
+            // sent before it opened, it's already open either way
+            (CircuitState::Open, _) => {}
+        }
+    }
+
+    /// How long until an open circuit lets a trial through, for the error message
+    pub fn retry_in(&self) -> Option<Duration> {
+        let circuit = self.circuit();
+        match (circuit.state, circuit.opened_at) {
+            (CircuitState::Open, Some(opened_at)) => Some(
+                (opened_at + self.policy.reset_timeout).saturating_duration_since(Instant::now()),
+            ),
+            _ => None,
+        }
+    }
+
+    /// `{ 'state' => 'open', 'consecutive_failures' => 5, 'retry_in' => 12.5 }`,
+    /// retry_in is only there while open
+    pub fn to_rhash(&self) -> Result<RHash, magnus::Error> {
+        let retry_in = self.retry_in();
+        let circuit = self.circuit();
+
+        let rb_hash = RHash::new();
+        rb_hash.aset("state", circuit.state.as_str())?;
+        rb_hash.aset("consecutive_failures", circuit.consecutive_failures)?;
+        if let Some(retry_in) = retry_in {
+            rb_hash.aset("retry_in", retry_in.as_secs_f64())?;
+        }
+        Ok(rb_hash)
+    }
+
+    fn is_cooling_down(&self, circuit: &Circuit, now: Instant) -> bool {
+        match circuit.opened_at {
+            Some(opened_at) => now < opened_at + self.policy.reset_timeout,
+            None => false,
+        }
+    }
+
+    fn circuit(&self) -> MutexGuard<'_, Circuit> {
+        self.circuit.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A request the circuit let through. Gives a half open circuit's trial back
+/// when dropped, so one that never finishes doesn't keep the circuit shut
+#[derive(Default)]
+pub struct Admission<'a> {
+    circuit: Option<&'a CircuitBreaker>,
+    trial: bool,
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        if let (Some(breaker), true) = (self.circuit, self.trial) {
+            let mut circuit = breaker.circuit();
+            if circuit.state == CircuitState::HalfOpen {
+                circuit.trials = circuit.trials.saturating_sub(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use std::future::Future;
+
+use magnus::{block, RHash, Value};
+use tokio::sync::{mpsc, Notify};
+
+use crate::gvl;
+
+use super::api::{batch_send_api_requests, stream_api_requests};
+use super::auth::{Auth, Credentials};
+use super::cache::{DiskStore, MemoryCache, ResponseCache};
+use super::endpoint::Endpoints;
+use super::options::{BatchOptions, ClientOptions};
+use super::request::Request;
+use super::response::Response;
+
+// magnus converts the ruby hash to rust types for us
+pub type RbArrayOfHashes = Vec<HashMap<String, Value>>;
+
+/// Holds a long lived tokio runtime and reqwest client so keep-alive connections
+/// (and their TLS sessions) get reused from one batch to the next.
+/// Safe to share between ruby threads, but create it after forking
+/// since the runtime's threads don't make it into the child process.
+/// Wrapped for ruby by BatchApi::Vroom::Client and BatchApi::Osrm::Client
+pub struct Client {
+    runtime: tokio::runtime::Runtime,
+    http_client: reqwest::Client,
+    // kept between batches so ejected endpoints stay out of rotation
+    endpoints: Arc<Endpoints>,
+    // swapped out by Client#auth= while batches are in flight
+    credentials: Arc<Credentials>,
+    cache: Arc<ResponseCache>,
+    options: ClientOptions,
+}
+
+impl Client {
+    pub fn new(options: ClientOptions) -> Result<Self, magnus::Error> {
+        // a single worker drives the connections for every batch,
+        // each calling ruby thread blocks on its own batch future
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .max_blocking_threads(1)
+            .enable_all()
+            .build()
+            .map_err(|err| {
+                magnus::Error::new(magnus::exception::runtime_error(), err.to_string())
+            })?;
+        let http_client = options.build_http_client()?;
+        let endpoints = Endpoints::new(
+            options.endpoints.clone(),
+            options.balance,
+            options.ejection.clone(),
+            options.rate_limit.clone(),
+            options.circuit_breaker.clone(),
+        );
+        let store = match options.store.clone() {
+            Some(policy) => Some(DiskStore::open(policy)?),
+            None => None,
+        };
+        let cache = ResponseCache::new(options.cache.clone().map(MemoryCache::new), store);
+        let mut credentials = Credentials::new(options.auth.clone(), &options.headers);
+        if let Some(password) = options
+            .proxy
+            .as_ref()
+            .and_then(|proxy| proxy.password.clone())
+        {
+            credentials.add_secret(password);
+        }
+
+        Ok(Client {
+            runtime,
+            http_client,
+            endpoints: Arc::new(endpoints),
+            credentials: Arc::new(credentials),
+            cache: Arc::new(cache),
+            options,
+        })
+    }
+
+    /// `client.auth = { bearer: ... }` swaps the credentials, e.g. when a token is refreshed.
+    /// Requests already in flight finish with the old ones, retries and later batches use the new.
+    /// nil stops sending auth
+    pub fn rb_set_auth(&self, rb_auth: Option<RHash>) -> Result<(), magnus::Error> {
+        let auth = match rb_auth {
+            Some(rb_auth) => Some(Auth::from_rhash(rb_auth)?),
+            None => None,
+        };
+        self.credentials.set(auth);
+        Ok(())
+    }
+
+    /// `client.circuits` is each endpoint's circuit breaker by url, for health checks.
+    /// Endpoints only show up once they've been used, unless they're balanced
+    pub fn rb_circuits(&self) -> Result<RHash, magnus::Error> {
+        let rb_circuits = RHash::new();
+        for endpoint in self.endpoints.all() {
+            if let Some(circuit) = &endpoint.circuit {
+                rb_circuits.aset(endpoint.url.as_str(), circuit.to_rhash()?)?;
+            }
+        }
+        Ok(rb_circuits)
+    }
+
+    /// The base url for requests without a full url, the batch's then the client's,
+    /// and whether they're load balanced when there isn't one.
+    /// A batch's own base url wins over the client's endpoints
+    pub fn base_url_for<'a>(&'a self, options: &'a BatchOptions) -> (Option<&'a str>, bool) {
+        let base_url = options
+            .base_url
+            .as_deref()
+            .or(self.options.base_url.as_deref());
+        let balanced = options.base_url.is_none() && self.endpoints.is_balanced();
+        (base_url, balanced)
+    }
+
+    /// Sends requests already converted from ruby and converts the responses back.
+    /// With a block each response is yielded as `|index, response|` as soon as it finishes
+    /// and nil is returned, otherwise they're all returned in order once the batch is done
+    pub fn rb_send_requests(
+        &self,
+        requests: Vec<Request>,
+        options: &BatchOptions,
+    ) -> Result<Option<magnus::RArray>, magnus::Error> {
+        if block::block_given() {
+            self.stream_send(requests, options, |response| {
+                let index = response.sort_key;
+                block::yield_values::<(i32, RHash), Value>((index, response.into_rhash()?))?;
+                Ok(())
+            })?;
+            return Ok(None);
+        }
+
+        let rb_responses = self
+            .batch_send(requests, options)?
+            .into_iter()
+            .map(Response::into_rhash)
+            .collect::<Result<Vec<RHash>, magnus::Error>>()?;
+        Ok(Some(magnus::RArray::from_vec(rb_responses)))
+    }
+
+    /// Sends the batch on the client's runtime with the GVL released
+    /// while waiting on the network so other ruby threads keep running
+    pub fn batch_send(
+        &self,
+        requests: Vec<Request>,
+        options: &BatchOptions,
+    ) -> Result<Vec<Response>, magnus::Error> {
+        self.block_on_without_gvl(batch_send_api_requests(
+            self.http_client.clone(),
+            Arc::clone(&self.endpoints),
+            Arc::clone(&self.credentials),
+            &self.cache,
+            requests,
+            options,
+        ))
+    }
+
+    /// Sends the batch in the background on the client's runtime, calling `on_response`
+    /// on this thread with the GVL held as each one finishes. The GVL is only released
+    /// while waiting for the next response. If `on_response` errors the rest of the batch is aborted
+    pub fn stream_send(
+        &self,
+        requests: Vec<Request>,
+        options: &BatchOptions,
+        mut on_response: impl FnMut(Response) -> Result<(), magnus::Error>,
+    ) -> Result<(), magnus::Error> {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Response>();
+        let http_client = self.http_client.clone();
+        let endpoints = Arc::clone(&self.endpoints);
+        let credentials = Arc::clone(&self.credentials);
+        let cache = Arc::clone(&self.cache);
+        let options = options.clone();
+
+        // keeps going while ruby runs the block, the sender is dropped once every request has a response
+        let batch = self.runtime.spawn(async move {
+            stream_api_requests(
+                http_client,
+                endpoints,
+                credentials,
+                &cache,
+                requests,
+                &options,
+                |response| {
+                    // only fails if we stopped listening, in which case the batch is being aborted anyway
+                    let _ = sender.send(response);
+                },
+            )
+            .await
+        });
+        let _abort_batch = AbortOnDrop(batch.abort_handle());
+
+        while let Some(response) = self.block_on_without_gvl(receiver.recv())? {
+            on_response(response)?;
+        }
+        Ok(())
+    }
+
+    /// Runs a future to completion on the client's runtime with the GVL released
+    fn block_on_without_gvl<F: Future>(&self, future: F) -> Result<F::Output, magnus::Error> {
+        // ruby interrupts (Ctrl-C, Thread#raise) notify us from the unblock function,
+        // dropping the future aborts every request it has in flight
+        let interrupted = Notify::new();
+        let output = gvl::without_gvl(
+            || {
+                self.runtime.block_on(async {
+                    tokio::select! {
+                        output = future => Some(output),
+                        _ = interrupted.notified() => None,
+                    }
+                })
+            },
+            || interrupted.notify_one(),
+        )?;
+
+        // back with the GVL, let ruby raise whatever interrupted us
+        match output {
+            Some(output) => Ok(output),
+            None => {
+                gvl::check_interrupts()?;
+                let rb_error =
+                    magnus::Error::new(magnus::exception::runtime_error(), "batch was interrupted");
+                Err(rb_error)
+            }
+        }
+    }
+}
+
+// Aborts a batch streaming in the background when ruby stops listening early,
+// whether the block raised, broke out or the thread was interrupted
+struct AbortOnDrop(tokio::task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
@@ -0,0 +1,136 @@
+// Compresses request bodies, large problems with full matrices run to megabytes of json.
+// Responses are decompressed by reqwest itself, which asks for gzip or brotli on every request
+
+use std::io::{Read, Write};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use magnus::RHash;
+
+use crate::options::{fetch, fetch_name};
+
+// below this compressing costs more than the bytes it saves
+const DEFAULT_MIN_BYTES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gzip" => Some(Encoding::Gzip),
+            "br" | "brotli" => Some(Encoding::Brotli),
+            _ => None,
+        }
+    }
+
+    /// The Content-Encoding value
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    // brotli's top levels are far too slow for bodies sent on every solve
+    pub fn default_level(&self) -> u32 {
+        match self {
+            Encoding::Gzip => 6,
+            Encoding::Brotli => 5,
+        }
+    }
+
+    fn max_level(&self) -> u32 {
+        match self {
+            Encoding::Gzip => 9,
+            Encoding::Brotli => 11,
+        }
+    }
+
+    pub fn compress(&self, bytes: &[u8], level: u32) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            Encoding::Brotli => {
+                // 22 is the largest window brotli allows without going past the spec
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, level, 22);
+                encoder.write_all(bytes)?;
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            }
+        }
+    }
+
+    pub fn decompress(&self, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        match self {
+            Encoding::Gzip => GzDecoder::new(bytes).read_to_end(&mut decompressed)?,
+            Encoding::Brotli => {
+                brotli::Decompressor::new(bytes, 4096).read_to_end(&mut decompressed)?
+            }
+        };
+        Ok(decompressed)
+    }
+}
+
+/// How request bodies are compressed, and which are worth compressing
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionPolicy {
+    pub encoding: Encoding,
+    pub level: u32,
+    // bodies smaller than this go as they are
+    pub min_bytes: usize,
+}
+
+impl CompressionPolicy {
+    /// `{ encoding: :gzip, level: 6, min_bytes: 1024 }`, encoding is gzip or br
+    pub fn from_rhash(rb_hash: RHash) -> Result<Self, magnus::Error> {
+        let encoding = match fetch_name(rb_hash, "encoding")?.as_deref() {
+            None => Encoding::Gzip,
+            Some(name) => match Encoding::from_name(name) {
+                Some(encoding) => encoding,
+                None => {
+                    let rb_error = magnus::Error::new(
+                        magnus::exception::arg_error(),
+                        "compression encoding must be gzip or br",
+                    );
+                    return Err(rb_error);
+                }
+            },
+        };
+
+        let level = fetch::<u32>(rb_hash, "level")?.unwrap_or(encoding.default_level());
+        if level > encoding.max_level() {
+            let rb_error = magnus::Error::new(
+                magnus::exception::arg_error(),
+                format!(
+                    "{} compression level must be 0 to {}",
+                    encoding.as_str(),
+                    encoding.max_level()
+                ),
+            );
+            return Err(rb_error);
+        }
+
+        Ok(CompressionPolicy {
+            encoding,
+            level,
+            min_bytes: fetch::<usize>(rb_hash, "min_bytes")?.unwrap_or(DEFAULT_MIN_BYTES),
+        })
+    }
+
+    /// The compressed body, None when it's under the threshold and should go as it is
+    pub fn compress(&self, body: &str) -> Option<Vec<u8>> {
+        if body.len() < self.min_bytes {
+            return None;
+        }
+        // failing to compress into memory shouldn't happen, if it does send it uncompressed
+        self.encoding.compress(body.as_bytes(), self.level).ok()
+    }
+}
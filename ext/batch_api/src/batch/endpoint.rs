@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::circuit::{Admission, CircuitBreaker, CircuitBreakerPolicy};
+use super::rate_limit::{RateLimitPolicy, RateLimiter, Reservation};
+use super::response::{ErrorKind, Outcome};
+
+/// How the balancer picks which endpoint gets the next request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    LeastInFlight,
+}
+
+impl Strategy {
+    pub fn from_name(name: &str) -> Result<Self, magnus::Error> {
+        match name {
+            "round_robin" => Ok(Strategy::RoundRobin),
+            "least_in_flight" => Ok(Strategy::LeastInFlight),
+            _ => {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "balance must be round_robin or least_in_flight",
+                );
+                Err(rb_error)
+            }
+        }
+    }
+}
+
+/// Balanced endpoints failing this many times in a row
+/// are taken out of rotation until the cooldown is over
+#[derive(Debug, Clone, PartialEq)]
+pub struct EjectionPolicy {
+    pub after_failures: u32,
+    pub cooldown: Duration,
+}
+
+impl Default for EjectionPolicy {
+    fn default() -> Self {
+        EjectionPolicy {
+            after_failures: 3,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+/// An instance requests get sent to, and how it's been doing
+#[derive(Debug)]
+pub struct Endpoint {
+    pub url: String,
+    in_flight: AtomicUsize,
+    consecutive_failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    // None sends requests as fast as the concurrency limit allows
+    limiter: Option<RateLimiter>,
+    // None always sends, however the endpoint's been doing
+    pub circuit: Option<CircuitBreaker>,
+}
+
+impl Endpoint {
+    pub fn new(url: String, limiter: Option<RateLimiter>, circuit: Option<CircuitBreaker>) -> Self {
+        Endpoint {
+            url,
+            in_flight: AtomicUsize::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+            limiter,
+            circuit,
+        }
+    }
+
+    /// None when the endpoint's circuit is open and the request should fail fast.
+    /// Keep the admission until the outcome's recorded
+    pub fn admit(&self) -> Option<Admission<'_>> {
+        match &self.circuit {
+            Some(circuit) => circuit.admit(),
+            None => Some(Admission::default()),
+        }
+    }
+
+    /// Waits until the endpoint's rate limit lets another request through, None when it isn't limited.
+    /// The turn is handed back if it's dropped before being spent
+    pub async fn wait_for_turn(&self) -> Option<Reservation<'_>> {
+        let reservation = self.limiter.as_ref()?.reserve();
+        reservation.wait().await;
+        Some(reservation)
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped
+    pub fn start_request(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(Arc::clone(self))
+    }
+
+    /// Connection errors, timeouts and 5xx count against the endpoint,
+    /// anything else means it's up and resets the count
+    pub fn record_outcome(&self, outcome: &Outcome, ejection: &EjectionPolicy) {
+        let failed = match outcome {
+            Outcome::Http {
+                http_status_code, ..
+            } => *http_status_code >= 500,
+            Outcome::Error { error_kind, .. } => {
+                matches!(
+                    error_kind,
+                    ErrorKind::Connect | ErrorKind::Certificate | ErrorKind::Timeout
+                )
+            }
+        };
+
+        if let Some(circuit) = &self.circuit {
+            circuit.record(failed);
+        }
+
+        if !failed {
+            self.consecutive_failures.store(0, Ordering::SeqCst);
+            return;
+        }
+
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= ejection.after_failures {
+            self.consecutive_failures.store(0, Ordering::SeqCst);
+            *self.ejected_until() = Some(Instant::now() + ejection.cooldown);
+        }
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    // None once the endpoint is back in rotation
+    fn ejection_ends(&self, now: Instant) -> Option<Instant> {
+        let mut ejected_until = self.ejected_until();
+        match *ejected_until {
+            Some(until) if until > now => Some(until),
+            Some(_) => {
+                // cooldown is over, bring it back
+                *ejected_until = None;
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn circuit_rejecting(&self, now: Instant) -> bool {
+        self.circuit
+            .as_ref()
+            .is_some_and(|circuit| circuit.is_rejecting(now))
+    }
+
+    fn ejected_until(&self) -> std::sync::MutexGuard<'_, Option<Instant>> {
+        self.ejected_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Keeps an endpoint's in flight count up while a request is being sent
+pub struct InFlight(Arc<Endpoint>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Every endpoint a client sends to. The configured ones are load balanced,
+/// requests with their own url get an endpoint of their own so failures are still tracked
+#[derive(Debug, Default)]
+pub struct Endpoints {
+    balanced: Vec<Arc<Endpoint>>,
+    strategy: Strategy,
+    pub ejection: EjectionPolicy,
+    // fixed endpoints are made as they're first used, so keep these for them
+    rate_limit: RateLimitPolicy,
+    circuit_breaker: Option<CircuitBreakerPolicy>,
+    next: AtomicUsize,
+    fixed: Mutex<HashMap<String, Arc<Endpoint>>>,
+}
+
+impl Endpoints {
+    pub fn new(
+        urls: Vec<String>,
+        strategy: Strategy,
+        ejection: EjectionPolicy,
+        rate_limit: RateLimitPolicy,
+        circuit_breaker: Option<CircuitBreakerPolicy>,
+    ) -> Self {
+        let mut endpoints = Endpoints {
+            strategy,
+            ejection,
+            rate_limit,
+            circuit_breaker,
+            ..Default::default()
+        };
+        endpoints.balanced = urls
+            .into_iter()
+            .map(|url| Arc::new(endpoints.endpoint(url)))
+            .collect();
+        endpoints
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        !self.balanced.is_empty()
+    }
+
+    /// The endpoint for a url that isn't load balanced, made on first use
+    pub fn fixed(&self, url: &str) -> Arc<Endpoint> {
+        let mut fixed = self.fixed.lock().unwrap_or_else(PoisonError::into_inner);
+        let endpoint = fixed
+            .entry(url.to_string())
+            .or_insert_with(|| Arc::new(self.endpoint(url.to_string())));
+        Arc::clone(endpoint)
+    }
+
+    /// Every endpoint used so far, balanced ones first
+    pub fn all(&self) -> Vec<Arc<Endpoint>> {
+        let fixed = self.fixed.lock().unwrap_or_else(PoisonError::into_inner);
+        self.balanced
+            .iter()
+            .chain(fixed.values())
+            .map(Arc::clone)
+            .collect()
+    }
+
+    /// Picks a balanced endpoint, skipping ejected ones and those with an open circuit.
+    /// If they're all out the one due back soonest gets it rather than failing outright,
+    /// unless every circuit is open in which case the request fails fast
+    pub fn pick(&self) -> Option<Arc<Endpoint>> {
+        if self.balanced.is_empty() {
+            return None;
+        }
+
+        let now = Instant::now();
+        let start = self.next.fetch_add(1, Ordering::SeqCst);
+        // walk the endpoints starting from the next one in turn
+        let mut in_rotation = (0..self.balanced.len())
+            .map(|offset| &self.balanced[(start + offset) % self.balanced.len()])
+            .filter(|endpoint| {
+                endpoint.ejection_ends(now).is_none() && !endpoint.circuit_rejecting(now)
+            });
+
+        let picked = match self.strategy {
+            Strategy::RoundRobin => in_rotation.next(),
+            // min_by_key keeps the first of equals, so ties still go round robin
+            Strategy::LeastInFlight => in_rotation.min_by_key(|endpoint| endpoint.in_flight()),
+        };
+
+        let picked = picked.or_else(|| {
+            self.balanced.iter().min_by_key(|endpoint| {
+                (endpoint.circuit_rejecting(now), endpoint.ejection_ends(now))
+            })
+        });
+        picked.map(Arc::clone)
+    }
+
+    fn endpoint(&self, url: String) -> Endpoint {
+        let limiter = self.rate_limit.limiter_for(&url);
+        let circuit = self.circuit_breaker.clone().map(CircuitBreaker::new);
+        Endpoint::new(url, limiter, circuit)
+    }
+}
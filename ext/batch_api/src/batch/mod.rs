@@ -0,0 +1,18 @@
+// The machinery every batch is sent with, whatever api it's for.
+// vroom, osrm and plain http batches build their requests and read the responses on top of it
+
+pub(crate) mod api;
+pub(crate) mod auth;
+pub(crate) mod cache;
+mod circuit;
+pub(crate) mod client;
+pub(crate) mod compression;
+mod endpoint;
+pub(crate) mod options;
+mod rate_limit;
+pub(crate) mod request;
+pub(crate) mod response;
+mod retry;
+mod transport;
+
+pub mod cancellation;
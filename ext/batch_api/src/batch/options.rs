@@ -0,0 +1,263 @@
+use std::time::Duration;
+
+use magnus::{RHash, Value};
+use reqwest::header::HeaderMap;
+
+use crate::options::{fetch, fetch_duration, fetch_name};
+
+use super::auth::{header_map, Auth};
+use super::cache::{CachePolicy, StorePolicy};
+use super::cancellation::CancellationToken;
+use super::circuit::CircuitBreakerPolicy;
+use super::compression::CompressionPolicy;
+use super::endpoint::{EjectionPolicy, Strategy};
+use super::rate_limit::RateLimitPolicy;
+use super::retry::RetryPolicy;
+use super::transport::{ProxyPolicy, TlsPolicy};
+
+/// Options for building a client, fixed for the client's lifetime
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ClientOptions {
+    // used instead of the VROOM_URL or OSRM_URL env var when set,
+    // requests with a relative url are joined onto it
+    pub base_url: Option<String>,
+    // instances to load balance requests without a full url across,
+    // takes over from base_url when given
+    pub endpoints: Vec<String>,
+    pub balance: Strategy,
+    pub ejection: EjectionPolicy,
+    // idle keep-alive connections kept per host
+    pub pool_size: Option<usize>,
+    // timeouts are given in seconds from ruby, None means wait forever
+    pub connect_timeout: Option<Duration>,
+    // default for every batch, a batch's own request_timeout wins
+    pub request_timeout: Option<Duration>,
+    // nested `cache: { ... }` hash, None doesn't cache responses
+    pub cache: Option<CachePolicy>,
+    // nested `store: { path: ... }` hash, None doesn't keep responses on disk
+    pub store: Option<StorePolicy>,
+    // sent with every request, credentials among them marked sensitive
+    pub headers: HeaderMap,
+    // nested `auth: { bearer: ... }` hash, can be swapped later with Client#auth=
+    pub auth: Option<Auth>,
+    // nested `tls: { ca_file: ... }` hash, None uses the system's roots
+    pub tls: Option<TlsPolicy>,
+    // proxy url or `proxy: { url: ... }` hash, None goes by the proxy env vars
+    pub proxy: Option<ProxyPolicy>,
+    // nested `rate_limit: { ... }` hash, endpoints aren't limited by default
+    pub rate_limit: RateLimitPolicy,
+    // nested `circuit_breaker: { ... }` hash, None never stops sending to an endpoint
+    pub circuit_breaker: Option<CircuitBreakerPolicy>,
+}
+
+impl ClientOptions {
+    pub fn from_rhash(rb_hash: Option<RHash>) -> Result<Self, magnus::Error> {
+        let mut options = Self::default();
+
+        // no options hash passed, keep the defaults
+        let rb_hash = match rb_hash {
+            Some(rb_hash) => rb_hash,
+            None => return Ok(options),
+        };
+
+        options.base_url = fetch::<String>(rb_hash, "base_url")?;
+
+        if let Some(endpoints) = fetch::<Vec<String>>(rb_hash, "endpoints")? {
+            for endpoint in endpoints.iter() {
+                if let Err(err) = reqwest::Url::parse(endpoint) {
+                    let rb_error = magnus::Error::new(
+                        magnus::exception::arg_error(),
+                        format!("invalid endpoint {}: {}", endpoint, err),
+                    );
+                    return Err(rb_error);
+                }
+            }
+            options.endpoints = endpoints;
+        }
+
+        if let Some(balance) = fetch_name(rb_hash, "balance")? {
+            options.balance = Strategy::from_name(&balance)?;
+        }
+
+        if let Some(after_failures) = fetch::<u32>(rb_hash, "eject_after")? {
+            if after_failures == 0 {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "eject_after must be greater than 0",
+                );
+                return Err(rb_error);
+            }
+            options.ejection.after_failures = after_failures;
+        }
+
+        if let Some(cooldown) = fetch_duration(rb_hash, "eject_for")? {
+            options.ejection.cooldown = cooldown;
+        }
+
+        options.pool_size = fetch::<usize>(rb_hash, "pool_size")?;
+        options.connect_timeout = fetch_duration(rb_hash, "connect_timeout")?;
+        options.request_timeout = fetch_duration(rb_hash, "request_timeout")?;
+
+        if let Some(rb_cache_hash) = fetch::<RHash>(rb_hash, "cache")? {
+            options.cache = Some(CachePolicy::from_rhash(rb_cache_hash)?);
+        }
+
+        if let Some(rb_store_hash) = fetch::<RHash>(rb_hash, "store")? {
+            options.store = Some(StorePolicy::from_rhash(rb_store_hash)?);
+        }
+
+        if let Some(rb_headers) = fetch::<RHash>(rb_hash, "headers")? {
+            options.headers = header_map(rb_headers)?;
+        }
+
+        if let Some(rb_auth_hash) = fetch::<RHash>(rb_hash, "auth")? {
+            options.auth = Some(Auth::from_rhash(rb_auth_hash)?);
+        }
+
+        if let Some(rb_tls_hash) = fetch::<RHash>(rb_hash, "tls")? {
+            options.tls = Some(TlsPolicy::from_rhash(rb_tls_hash)?);
+        }
+
+        if let Some(rb_proxy) = fetch::<Value>(rb_hash, "proxy")? {
+            options.proxy = Some(ProxyPolicy::from_value(rb_proxy)?);
+        }
+
+        if let Some(rb_rate_limit_hash) = fetch::<RHash>(rb_hash, "rate_limit")? {
+            options.rate_limit = RateLimitPolicy::from_rhash(rb_rate_limit_hash)?;
+        }
+
+        if let Some(rb_circuit_breaker_hash) = fetch::<RHash>(rb_hash, "circuit_breaker")? {
+            options.circuit_breaker =
+                Some(CircuitBreakerPolicy::from_rhash(rb_circuit_breaker_hash)?);
+        }
+
+        Ok(options)
+    }
+
+    /// Builds the reqwest client batches are sent with
+    pub fn build_http_client(&self) -> Result<reqwest::Client, magnus::Error> {
+        let mut builder = reqwest::Client::builder();
+
+        if let Some(pool_size) = self.pool_size {
+            builder = builder.pool_max_idle_per_host(pool_size);
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+        if let Some(request_timeout) = self.request_timeout {
+            builder = builder.timeout(request_timeout);
+        }
+        if !self.headers.is_empty() {
+            builder = builder.default_headers(self.headers.clone());
+        }
+        if let Some(tls) = &self.tls {
+            builder = tls.apply(builder)?;
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = proxy.apply(builder)?;
+        }
+
+        builder
+            .build()
+            .map_err(|err| magnus::Error::new(magnus::exception::runtime_error(), err.to_string()))
+    }
+}
+
+/// Options that tune how a single batch of requests is sent
+#[derive(Debug, Default, Clone)]
+pub struct BatchOptions {
+    // overrides the client's base url for this batch
+    pub base_url: Option<String>,
+    // None means every request is sent at once
+    pub max_concurrency: Option<usize>,
+    // overrides the client's request timeout for this batch
+    pub request_timeout: Option<Duration>,
+    // deadline for the whole batch, unfinished requests come back timed out
+    pub batch_timeout: Option<Duration>,
+    // nested `retry: { ... }` hash, defaults to a single attempt
+    pub retry: RetryPolicy,
+    // nested `compression: { ... }` hash, None sends bodies uncompressed
+    pub compression: Option<CompressionPolicy>,
+    // lowercase names of the response headers handed back with each response
+    pub response_headers: Vec<String>,
+    // `cancellation_token: token` stops the batch when cancelled, from any thread
+    pub cancellation_token: Option<CancellationToken>,
+}
+
+impl BatchOptions {
+    pub fn from_rhash(rb_hash: Option<RHash>) -> Result<Self, magnus::Error> {
+        let mut options = Self::default();
+
+        // no options hash passed, keep the defaults
+        let rb_hash = match rb_hash {
+            Some(rb_hash) => rb_hash,
+            None => return Ok(options),
+        };
+
+        options.base_url = fetch::<String>(rb_hash, "base_url")?;
+
+        if let Some(max_concurrency) = fetch::<usize>(rb_hash, "max_concurrency")? {
+            if max_concurrency == 0 {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "max_concurrency must be greater than 0",
+                );
+                return Err(rb_error);
+            }
+            options.max_concurrency = Some(max_concurrency);
+        }
+
+        options.request_timeout = fetch_duration(rb_hash, "request_timeout")?;
+        options.batch_timeout = fetch_duration(rb_hash, "batch_timeout")?;
+
+        if let Some(rb_retry_hash) = fetch::<RHash>(rb_hash, "retry")? {
+            options.retry = RetryPolicy::from_rhash(rb_retry_hash)?;
+        }
+
+        if let Some(rb_compression_hash) = fetch::<RHash>(rb_hash, "compression")? {
+            options.compression = Some(CompressionPolicy::from_rhash(rb_compression_hash)?);
+        }
+
+        if let Some(response_headers) = fetch::<Vec<String>>(rb_hash, "response_headers")? {
+            for name in response_headers.iter() {
+                if let Err(err) = reqwest::header::HeaderName::from_bytes(name.as_bytes()) {
+                    let rb_error = magnus::Error::new(
+                        magnus::exception::arg_error(),
+                        format!("invalid response header {}: {}", name, err),
+                    );
+                    return Err(rb_error);
+                }
+            }
+            options.response_headers = response_headers
+                .into_iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect();
+        }
+
+        options.cancellation_token =
+            fetch::<&CancellationToken>(rb_hash, "cancellation_token")?.cloned();
+
+        Ok(options)
+    }
+}
+
+/// For http and osrm batches. Cassettes match requests by body alone,
+/// which doesn't work for GETs, and their responses aren't vroom solutions
+pub fn reject_vroom_only(rb_hash: Option<RHash>) -> Result<(), magnus::Error> {
+    let rb_hash = match rb_hash {
+        Some(rb_hash) => rb_hash,
+        None => return Ok(()),
+    };
+
+    let cassette = fetch::<Value>(rb_hash, "cassette")?.is_some();
+    let parse_solutions = fetch::<bool>(rb_hash, "parse_solutions")?.unwrap_or(false)
+        || fetch::<bool>(rb_hash, "raise_vroom_errors")?.unwrap_or(false);
+    if cassette || parse_solutions {
+        let rb_error = magnus::Error::new(
+            magnus::exception::arg_error(),
+            "cassette, parse_solutions and raise_vroom_errors are only for vroom batches",
+        );
+        return Err(rb_error);
+    }
+    Ok(())
+}
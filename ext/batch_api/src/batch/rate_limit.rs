@@ -0,0 +1,180 @@
+// Token buckets that keep requests to an endpoint under a requests per second quota,
+// holding them back rather than sending them to be turned away with a 429
+
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use magnus::RHash;
+
+use crate::options::fetch;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub requests_per_second: f64,
+    // how many requests can go at once after a quiet spell
+    pub burst: u32,
+}
+
+impl Rate {
+    fn from_rhash(rb_hash: RHash) -> Result<Option<Self>, magnus::Error> {
+        let requests_per_second = match fetch::<f64>(rb_hash, "requests_per_second")? {
+            Some(rate) if rate > 0.0 && rate.is_finite() => rate,
+            Some(_) => {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "requests_per_second must be greater than 0",
+                );
+                return Err(rb_error);
+            }
+            None => return Ok(None),
+        };
+
+        let burst = match fetch::<u32>(rb_hash, "burst")? {
+            Some(0) => {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "burst must be greater than 0",
+                );
+                return Err(rb_error);
+            }
+            // evenly spaced unless told otherwise, the safest against strict quotas
+            burst => burst.unwrap_or(1),
+        };
+
+        Ok(Some(Rate {
+            requests_per_second,
+            burst,
+        }))
+    }
+}
+
+/// `rate_limit: { requests_per_second: 10, burst: 5, endpoints: { 'http://vroom:3000' => { requests_per_second: 2 } } }`.
+/// The top level rate applies to each endpoint separately, endpoints listed by url get their own
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RateLimitPolicy {
+    pub default: Option<Rate>,
+    // by base url without a trailing slash
+    pub endpoints: HashMap<String, Rate>,
+}
+
+impl RateLimitPolicy {
+    pub fn from_rhash(rb_hash: RHash) -> Result<Self, magnus::Error> {
+        let mut policy = RateLimitPolicy {
+            default: Rate::from_rhash(rb_hash)?,
+            ..Default::default()
+        };
+
+        if let Some(rb_endpoints) = fetch::<RHash>(rb_hash, "endpoints")? {
+            for (url, rb_rate) in rb_endpoints.to_hash_map::<String, RHash>()? {
+                let rate = match Rate::from_rhash(rb_rate)? {
+                    Some(rate) => rate,
+                    None => {
+                        let rb_error = magnus::Error::new(
+                            magnus::exception::arg_error(),
+                            format!("rate limit for {} needs requests_per_second", url),
+                        );
+                        return Err(rb_error);
+                    }
+                };
+                policy
+                    .endpoints
+                    .insert(url.trim_end_matches('/').to_string(), rate);
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// A bucket for the endpoint if it's limited
+    pub fn limiter_for(&self, url: &str) -> Option<RateLimiter> {
+        self.endpoints
+            .get(url.trim_end_matches('/'))
+            .or(self.default.as_ref())
+            .map(|rate| RateLimiter::new(*rate))
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: Rate,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // below zero when requests are queued waiting on tokens
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Rate) -> Self {
+        RateLimiter {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate.burst as f64,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token straight away, the reservation says when it's really ours.
+    /// Taking it up front queues requests in the order they asked, each behind the ones before
+    pub fn reserve(&self) -> Reservation<'_> {
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let now = Instant::now();
+        let refilled =
+            now.duration_since(bucket.refilled_at).as_secs_f64() * self.rate.requests_per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(self.rate.burst as f64);
+        bucket.refilled_at = now;
+
+        bucket.tokens -= 1.0;
+        let wait = if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.rate.requests_per_second)
+        };
+
+        Reservation {
+            limiter: self,
+            ready_at: now + wait,
+            spent: false,
+        }
+    }
+
+    // a token that was never used goes back so the next request can have it
+    fn refund(&self) {
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+        bucket.tokens = (bucket.tokens + 1.0).min(self.rate.burst as f64);
+    }
+}
+
+/// A token taken from the bucket. Dropping it before it's spent hands the token back,
+/// e.g. when the batch is cancelled or times out while the request is still waiting
+pub struct Reservation<'a> {
+    limiter: &'a RateLimiter,
+    ready_at: Instant,
+    spent: bool,
+}
+
+impl Reservation<'_> {
+    /// Waits until the token is really ours
+    pub async fn wait(&self) {
+        tokio::time::sleep_until(self.ready_at.into()).await;
+    }
+
+    /// The request is going out, so the token is kept
+    pub fn spend(mut self) {
+        self.spent = true;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if !self.spent {
+            self.limiter.refund();
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use reqwest::header::HeaderMap;
+use reqwest::Method;
+
+/// Represents an API request to be sent in a batch, to vroom, osrm or any other json api
+#[derive(Debug)]
+pub struct Request {
+    pub method: Method,
+    // None leaves picking the endpoint to the client's load balancer
+    pub base_url: Option<String>,
+    // joined onto the base url, empty to post to the base url itself
+    pub path: String,
+    // added to the url on top of any query string already in the path, sorted by name
+    pub query: Vec<(String, String)>,
+    // empty sends no body and no content type, e.g. for GETs
+    pub body: String,
+    // sent on top of the client's headers, replacing any with the same name
+    pub headers: HeaderMap,
+}
+
+/// Url::join drops the base's last path segment unless it ends in a slash,
+/// we always want the path tacked onto the end e.g. http://vroom/uk + solve
+pub fn join_url(base_url: &str, path: &str) -> String {
+    if path.is_empty() {
+        return base_url.to_string();
+    }
+
+    format!(
+        "{}/{}",
+        base_url.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
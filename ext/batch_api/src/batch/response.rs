@@ -0,0 +1,177 @@
+use std::time::Duration;
+
+use magnus::{IntoValue, RArray, RHash, Value};
+
+// Sort id is to optionally sort responses
+// in the same order they were sent, it's also the request's index
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub sort_key: i32,
+    pub outcome: Outcome,
+    // how many times the request was sent, 0 if it never finished
+    pub attempts: u32,
+    // base url of the endpoint the last attempt went to
+    pub endpoint: Option<String>,
+    // how long the last attempt took, None if it never finished
+    pub latency: Option<Duration>,
+    // time spent waiting on the concurrency limit and rate limit, over every attempt
+    pub queue_wait: Option<Duration>,
+    // answered from the cache, or by an identical request in the same batch
+    pub cache_hit: bool,
+}
+
+/// Either the server answered with an http status or the request failed before it could
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Http {
+        http_status_code: u16,
+        body: String,
+        // the response headers the batch asked for, with lowercase names
+        headers: Vec<(String, String)>,
+    },
+    Error {
+        error_kind: ErrorKind,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Connect,
+    // the server's certificate wasn't trusted, or it didn't accept ours
+    Certificate,
+    Timeout,
+    BodyRead,
+    // reqwest wouldn't send the request as it was, e.g. it was redirected too many times
+    Request,
+    Panic,
+    // not sent, the endpoint's circuit breaker is open
+    CircuitOpen,
+    // the batch's cancellation token was cancelled before it finished
+    Cancelled,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Connect => "connect",
+            ErrorKind::Certificate => "certificate",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::BodyRead => "body_read",
+            ErrorKind::Request => "request",
+            ErrorKind::Panic => "panic",
+            ErrorKind::CircuitOpen => "circuit_open",
+            ErrorKind::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        let error_kind = match name {
+            "connect" => ErrorKind::Connect,
+            "certificate" => ErrorKind::Certificate,
+            "timeout" => ErrorKind::Timeout,
+            "body_read" => ErrorKind::BodyRead,
+            "request" => ErrorKind::Request,
+            "panic" => ErrorKind::Panic,
+            "circuit_open" => ErrorKind::CircuitOpen,
+            "cancelled" => ErrorKind::Cancelled,
+            _ => return None,
+        };
+        Some(error_kind)
+    }
+}
+
+impl Response {
+    /// Error response for a request whose task never finished, so attempts aren't known
+    pub fn error(sort_key: i32, error_kind: ErrorKind, message: String) -> Self {
+        Response {
+            sort_key,
+            outcome: Outcome::Error {
+                error_kind,
+                message,
+            },
+            attempts: 0,
+            endpoint: None,
+            latency: None,
+            queue_wait: None,
+            cache_hit: false,
+        }
+    }
+
+    /// consumes self and returns the ruby hash handed back for the request
+    pub fn into_rhash(self) -> Result<RHash, magnus::Error> {
+        let rb_hash = RHash::new();
+        rb_hash.aset("index", self.sort_key)?;
+        // Insert hash for all fields on Request
+        match self.outcome {
+            Outcome::Http {
+                http_status_code,
+                body,
+                headers,
+            } => {
+                rb_hash.aset("body", body)?;
+                rb_hash.aset("http_status_code", http_status_code)?;
+
+                let rb_headers = RHash::new();
+                for (name, value) in headers {
+                    rb_headers.aset(name, value)?;
+                }
+                rb_hash.aset("headers", rb_headers)?;
+            }
+            Outcome::Error {
+                error_kind,
+                message,
+            } => {
+                rb_hash.aset("error_kind", error_kind.as_str())?;
+                rb_hash.aset("error_message", message)?;
+            }
+        }
+        rb_hash.aset("attempts", self.attempts)?;
+        if let Some(endpoint) = self.endpoint {
+            rb_hash.aset("endpoint", endpoint)?;
+        }
+        if let Some(latency) = self.latency {
+            rb_hash.aset("latency_ms", latency.as_secs_f64() * 1000.0)?;
+        }
+        if let Some(queue_wait) = self.queue_wait {
+            rb_hash.aset("queue_wait_ms", queue_wait.as_secs_f64() * 1000.0)?;
+        }
+        rb_hash.aset("cache_hit", self.cache_hit)?;
+
+        Ok(rb_hash)
+    }
+}
+
+/// Nested ruby hashes and arrays with string keys, like JSON.parse gives
+pub fn json_into_ruby(json: serde_json::Value) -> Result<Value, magnus::Error> {
+    let value = match json {
+        serde_json::Value::Null => ().into_value(),
+        serde_json::Value::Bool(boolean) => boolean.into_value(),
+        serde_json::Value::Number(number) => {
+            if let Some(integer) = number.as_i64() {
+                integer.into_value()
+            } else if let Some(integer) = number.as_u64() {
+                integer.into_value()
+            } else {
+                number.as_f64().unwrap_or_default().into_value()
+            }
+        }
+        serde_json::Value::String(string) => string.into_value(),
+        serde_json::Value::Array(items) => {
+            let rb_array = RArray::with_capacity(items.len());
+            for item in items {
+                rb_array.push(json_into_ruby(item)?)?;
+            }
+            rb_array.into_value()
+        }
+        serde_json::Value::Object(map) => {
+            let rb_hash = RHash::new();
+            for (key, item) in map {
+                rb_hash.aset(key, json_into_ruby(item)?)?;
+            }
+            rb_hash.into_value()
+        }
+    };
+    Ok(value)
+}
@@ -0,0 +1,128 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use magnus::RHash;
+
+use crate::options::{fetch, fetch_duration};
+
+use super::response::{ErrorKind, Outcome};
+
+/// When and how often failed requests get sent again
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // includes the first attempt, so 1 means never retry
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    // fraction of each delay that is randomised, 0.0 to 1.0
+    pub jitter: f64,
+    pub retry_statuses: Vec<u16>,
+    pub honor_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.0,
+            retry_statuses: vec![502, 503, 504],
+            honor_retry_after: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_rhash(rb_hash: RHash) -> Result<Self, magnus::Error> {
+        let mut policy = Self::default();
+
+        if let Some(max_attempts) = fetch::<u32>(rb_hash, "max_attempts")? {
+            if max_attempts == 0 {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "max_attempts must be greater than 0",
+                );
+                return Err(rb_error);
+            }
+            policy.max_attempts = max_attempts;
+        }
+
+        if let Some(base_delay) = fetch_duration(rb_hash, "base_delay")? {
+            policy.base_delay = base_delay;
+        }
+
+        if let Some(max_delay) = fetch_duration(rb_hash, "max_delay")? {
+            policy.max_delay = max_delay;
+        }
+
+        if let Some(jitter) = fetch::<f64>(rb_hash, "jitter")? {
+            if !(0.0..=1.0).contains(&jitter) {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "jitter must be between 0.0 and 1.0",
+                );
+                return Err(rb_error);
+            }
+            policy.jitter = jitter;
+        }
+
+        if let Some(retry_statuses) = fetch::<Vec<u16>>(rb_hash, "retry_statuses")? {
+            policy.retry_statuses = retry_statuses;
+        }
+
+        if let Some(honor_retry_after) = fetch::<bool>(rb_hash, "retry_after")? {
+            policy.honor_retry_after = honor_retry_after;
+        }
+
+        Ok(policy)
+    }
+
+    /// Whether the outcome of an attempt is worth sending again,
+    /// ignores how many attempts have been made
+    pub fn is_retryable(&self, outcome: &Outcome) -> bool {
+        match outcome {
+            Outcome::Http {
+                http_status_code, ..
+            } => self.retry_statuses.contains(http_status_code),
+            // covers refused connections and ones reset before or while the body is read
+            Outcome::Error { error_kind, .. } => {
+                matches!(error_kind, ErrorKind::Connect | ErrorKind::BodyRead)
+            }
+        }
+    }
+
+    /// How long to wait before the next attempt, attempt starts from 1.
+    /// A Retry-After header from the server wins over the backoff if we honor it
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after.filter(|_| self.honor_retry_after) {
+            return retry_after.min(self.max_delay);
+        }
+
+        // base * 2^(attempt - 1), saturating so big attempt counts don't overflow
+        let exponent = attempt.saturating_sub(1).min(31);
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+
+        // take a random slice off the delay so retries from the same batch spread out
+        backoff.mul_f64(1.0 - self.jitter * random_fraction())
+    }
+}
+
+/// Parses a Retry-After header given in seconds,
+/// the http date form isn't something vroom or its proxies send us
+pub fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+    let seconds = value.trim().parse::<u64>().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+// Good enough randomness for jitter without pulling in a rand dependency,
+// RandomState is seeded randomly for every instance
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,247 @@
+// TLS and proxy settings for reaching instances on an internal CA
+// or through an egress proxy. Everything is read and checked when the client
+// is built, so a bad path or certificate raises from Client.new rather than failing each request
+
+use std::path::{Path, PathBuf};
+
+use magnus::{module, prelude::*, RArray, RHash, Value};
+use reqwest::tls::Version;
+
+use crate::options::{fetch, fetch_name};
+
+/// `tls: { ca_file: ..., cert_file: ..., key_file: ..., min_version: '1.2', insecure: false }`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TlsPolicy {
+    // pem bundle trusted on top of the system's roots
+    pub ca_file: Option<PathBuf>,
+    // pem certificate and pkcs8 key presented for mutual tls
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    pub min_version: Option<Version>,
+    // accepts any certificate for any host, never for production
+    pub insecure: bool,
+}
+
+impl TlsPolicy {
+    pub fn from_rhash(rb_hash: RHash) -> Result<Self, magnus::Error> {
+        let min_version = match fetch_name(rb_hash, "min_version")?.as_deref() {
+            None => None,
+            Some("1.0") => Some(Version::TLS_1_0),
+            Some("1.1") => Some(Version::TLS_1_1),
+            Some("1.2") => Some(Version::TLS_1_2),
+            Some("1.3") => Some(Version::TLS_1_3),
+            Some(_) => {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "tls min_version must be 1.0, 1.1, 1.2 or 1.3",
+                );
+                return Err(rb_error);
+            }
+        };
+
+        let policy = TlsPolicy {
+            ca_file: fetch::<String>(rb_hash, "ca_file")?.map(PathBuf::from),
+            cert_file: fetch::<String>(rb_hash, "cert_file")?.map(PathBuf::from),
+            key_file: fetch::<String>(rb_hash, "key_file")?.map(PathBuf::from),
+            min_version,
+            insecure: fetch::<bool>(rb_hash, "insecure")?.unwrap_or(false),
+        };
+
+        if policy.cert_file.is_some() != policy.key_file.is_some() {
+            let rb_error = magnus::Error::new(
+                magnus::exception::arg_error(),
+                "tls cert_file and key_file must be given together",
+            );
+            return Err(rb_error);
+        }
+
+        Ok(policy)
+    }
+
+    pub fn apply(
+        &self,
+        mut builder: reqwest::ClientBuilder,
+    ) -> Result<reqwest::ClientBuilder, magnus::Error> {
+        if let Some(ca_file) = &self.ca_file {
+            let certificates = reqwest::Certificate::from_pem_bundle(&read_file(ca_file)?)
+                .map_err(|err| invalid_file(ca_file, err))?;
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        if let (Some(cert_file), Some(key_file)) = (&self.cert_file, &self.key_file) {
+            let identity =
+                reqwest::Identity::from_pkcs8_pem(&read_file(cert_file)?, &read_file(key_file)?)
+                    .map_err(|err| invalid_file(cert_file, err))?;
+            builder = builder.identity(identity);
+        }
+
+        if let Some(min_version) = self.min_version {
+            builder = builder.min_tls_version(min_version);
+        }
+
+        if self.insecure {
+            // loud on purpose, this should never make it out of development
+            warn("BatchApi client is not verifying tls certificates (tls: { insecure: true })")?;
+            builder = builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+
+        Ok(builder)
+    }
+}
+
+/// `proxy: { url: ..., no_proxy: ['localhost', '.internal'], username: ..., password: ... }`
+/// or just the url. Without it the usual HTTP(S)_PROXY and NO_PROXY env vars are used
+#[derive(Default, Clone, PartialEq)]
+pub struct ProxyPolicy {
+    pub url: String,
+    // comma separated hosts, domains and ip ranges that skip the proxy
+    pub no_proxy: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+// written by hand so the password never ends up in debug output
+impl std::fmt::Debug for ProxyPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyPolicy")
+            .field("url", &self.url)
+            .field("no_proxy", &self.no_proxy)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "[REDACTED]"))
+            .finish()
+    }
+}
+
+impl ProxyPolicy {
+    pub fn from_value(value: Value) -> Result<Self, magnus::Error> {
+        let rb_hash = match RHash::from_value(value) {
+            Some(rb_hash) => rb_hash,
+            None => {
+                return Ok(ProxyPolicy {
+                    url: String::try_convert(value)?,
+                    ..Default::default()
+                })
+            }
+        };
+
+        let url = match fetch::<String>(rb_hash, "url")? {
+            Some(url) => url,
+            None => {
+                let rb_error =
+                    magnus::Error::new(magnus::exception::arg_error(), "proxy needs a url");
+                return Err(rb_error);
+            }
+        };
+
+        // a list of hosts or the comma separated string NO_PROXY takes
+        let no_proxy = match fetch::<Value>(rb_hash, "no_proxy")? {
+            Some(value) => match RArray::from_value(value) {
+                Some(hosts) => Some(hosts.to_vec::<String>()?.join(",")),
+                None => Some(String::try_convert(value)?),
+            },
+            None => None,
+        };
+
+        Ok(ProxyPolicy {
+            url,
+            no_proxy,
+            username: fetch::<String>(rb_hash, "username")?,
+            password: fetch::<String>(rb_hash, "password")?,
+        })
+    }
+
+    pub fn apply(
+        &self,
+        builder: reqwest::ClientBuilder,
+    ) -> Result<reqwest::ClientBuilder, magnus::Error> {
+        let mut proxy = reqwest::Proxy::all(&self.url).map_err(|err| {
+            magnus::Error::new(
+                magnus::exception::arg_error(),
+                format!("invalid proxy url: {}", err),
+            )
+        })?;
+
+        if let Some(username) = &self.username {
+            proxy = proxy.basic_auth(username, self.password.as_deref().unwrap_or_default());
+        }
+        if let Some(no_proxy) = &self.no_proxy {
+            proxy = proxy.no_proxy(reqwest::NoProxy::from_string(no_proxy));
+        }
+
+        Ok(builder.proxy(proxy))
+    }
+}
+
+/// Whether a failed request was down to a certificate, ours or the server's.
+/// reqwest doesn't say so itself, the tls backend's error is somewhere down the source chain
+pub fn is_certificate_error(err: &reqwest::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(err);
+    while let Some(err) = source {
+        if is_tls_certificate_error(err) {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+// native-tls uses openssl on linux, its errors carry the reason the handshake failed
+#[cfg(not(any(target_os = "windows", target_vendor = "apple")))]
+fn is_tls_certificate_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    match err.downcast_ref::<openssl::error::ErrorStack>() {
+        Some(error_stack) => error_stack.errors().iter().any(|error| {
+            error.library_code() == ERR_LIB_SSL
+                && CERTIFICATE_REASONS.contains(&error.reason_code())
+        }),
+        None => false,
+    }
+}
+
+// schannel and security framework errors don't give the reason in a way we can match on,
+// so on windows and macos this falls back to what their messages say
+#[cfg(any(target_os = "windows", target_vendor = "apple"))]
+fn is_tls_certificate_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.to_string().to_ascii_lowercase().contains("certificate")
+}
+
+// the same reason codes mean something else coming from other parts of openssl
+#[cfg(not(any(target_os = "windows", target_vendor = "apple")))]
+const ERR_LIB_SSL: i32 = 20;
+
+// OpenSSL's reason codes from sslerr.h. Alerts from the other end are 1000 plus the alert
+#[cfg(not(any(target_os = "windows", target_vendor = "apple")))]
+const CERTIFICATE_REASONS: &[i32] = &[
+    134,  // certificate verify failed, we didn't trust the server's
+    1042, // bad certificate
+    1043, // unsupported certificate
+    1044, // certificate revoked
+    1045, // certificate expired
+    1046, // certificate unknown
+    1048, // unknown ca, the server didn't trust ours
+    1116, // certificate required, the server wanted one and we didn't send it
+];
+
+fn read_file(path: &Path) -> Result<Vec<u8>, magnus::Error> {
+    std::fs::read(path).map_err(|err| {
+        magnus::Error::new(
+            magnus::exception::io_error(),
+            format!("can't read {}: {}", path.display(), err),
+        )
+    })
+}
+
+fn invalid_file(path: &Path, err: reqwest::Error) -> magnus::Error {
+    magnus::Error::new(
+        magnus::exception::arg_error(),
+        format!("invalid certificate or key in {}: {}", path.display(), err),
+    )
+}
+
+fn warn(message: &str) -> Result<(), magnus::Error> {
+    module::kernel().funcall::<_, _, Value>("warn", (message,))?;
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use std::ffi::c_void;
+use std::panic::{self, AssertUnwindSafe};
+
+use rb_sys::{rb_thread_call_without_gvl, rb_thread_check_ints, Qnil, VALUE};
+
+// What gets handed through ruby to the function running without the GVL
+struct Call<F, R> {
+    func: Option<F>,
+    result: Option<std::thread::Result<R>>,
+}
+
+/// Runs `func` with the GVL released so other ruby threads can carry on meanwhile.
+/// `unblock` gets called (possibly from another thread) when ruby wants to interrupt
+/// this one, e.g. Ctrl-C or Thread#raise, and has to make `func` return promptly.
+/// Neither closure can touch ruby objects, convert everything before and after.
+/// Errors if ruby raised a pending interrupt before `func` got to run
+pub fn without_gvl<F, R, U>(func: F, unblock: U) -> Result<R, magnus::Error>
+where
+    F: FnOnce() -> R,
+    U: Fn() + Sync,
+{
+    let mut call: Call<F, R> = Call {
+        func: Some(func),
+        result: None,
+    };
+
+    // ruby checks for interrupts before releasing the lock and raises (longjmps)
+    // if there are any, protect turns that into an Err instead of skipping our frames
+    magnus::rb_sys::protect(|| {
+        unsafe {
+            rb_thread_call_without_gvl(
+                Some(call_func::<F, R>),
+                &mut call as *mut Call<F, R> as *mut c_void,
+                Some(call_unblock::<U>),
+                &unblock as *const U as *mut c_void,
+            );
+        }
+        Qnil as VALUE
+    })?;
+
+    match call.result {
+        Some(Ok(result)) => Ok(result),
+        // the GVL is held again so it's safe to carry on panicking
+        Some(Err(panic_payload)) => panic::resume_unwind(panic_payload),
+        None => Err(magnus::Error::new(
+            magnus::exception::runtime_error(),
+            "interrupted before the GVL could be released",
+        )),
+    }
+}
+
+/// Raises any interrupt ruby has pending for this thread, like the one
+/// that made `without_gvl` call `unblock`
+pub fn check_interrupts() -> Result<(), magnus::Error> {
+    magnus::rb_sys::protect(|| {
+        unsafe { rb_thread_check_ints() };
+        Qnil as VALUE
+    })?;
+    Ok(())
+}
+
+// panics can't unwind into ruby's C frames so they're caught and resumed later
+unsafe extern "C" fn call_func<F, R>(data: *mut c_void) -> *mut c_void
+where
+    F: FnOnce() -> R,
+{
+    let call = &mut *(data as *mut Call<F, R>);
+    if let Some(func) = call.func.take() {
+        call.result = Some(panic::catch_unwind(AssertUnwindSafe(func)));
+    }
+    std::ptr::null_mut()
+}
+
+unsafe extern "C" fn call_unblock<U>(data: *mut c_void)
+where
+    U: Fn() + Sync,
+{
+    let unblock = &*(data as *const U);
+    // nowhere to report a panic to from here, and it mustn't reach ruby
+    let _ = panic::catch_unwind(AssertUnwindSafe(unblock));
+}
@@ -0,0 +1,120 @@
+// Batches of plain json http calls, e.g. to the geocoder or other internal services.
+// They go through the same clients as vroom batches, so get the same runtime,
+// ordering, concurrency limit, retries and error responses
+
+use std::collections::HashMap;
+
+use magnus::{prelude::*, scan_args::scan_args, RHash, RString, TryConvert, Value};
+use reqwest::header::HeaderMap;
+use reqwest::Method;
+
+use crate::batch::api::client_for;
+use crate::batch::auth::header_map;
+use crate::batch::client::RbArrayOfHashes;
+use crate::batch::options::{reject_vroom_only, BatchOptions};
+use crate::batch::request::{join_url, Request};
+use crate::options::name;
+
+/// `BatchApi::Http.batch(requests, options)` with requests like
+/// `{ 'method' => :get, 'url' => 'https://geocoder/search', 'query' => { 'q' => 'York' }, 'headers' => { ... }, 'body' => ... }`.
+/// Takes the same options as vroom batches apart from the vroom specific ones,
+/// and an optional block to stream responses to as they finish
+pub fn rb_batch(args: &[Value]) -> Result<Option<magnus::RArray>, magnus::Error> {
+    let args = scan_args::<(RbArrayOfHashes,), (Option<RHash>,), (), (), (), ()>(args)?;
+    let (rb_array_of_hashes,) = args.required;
+    let (rb_options,) = args.optional;
+
+    reject_vroom_only(rb_options)?;
+    let options = BatchOptions::from_rhash(rb_options)?;
+
+    let mut requests: Vec<Request> = Vec::with_capacity(rb_array_of_hashes.len());
+    for rb_hash_as_rust_type in rb_array_of_hashes.into_iter() {
+        let request = request_from_hashmap(rb_hash_as_rust_type, options.base_url.as_deref())?;
+        requests.push(request);
+    }
+
+    client_for(rb_options)?.rb_send_requests(requests, &options)
+}
+
+/// `{ 'method' => 'GET', 'url' => ..., 'query' => { ... }, 'headers' => { ... }, 'body' => ... }`
+/// for `BatchApi::Http.batch`. Only the url is needed, a full url or a path joined onto
+/// the batch's base url. The method defaults to GET, bodies that aren't strings are sent as their to_json
+fn request_from_hashmap(
+    hashmap: HashMap<String, Value>,
+    base_url: Option<&str>,
+) -> Result<Request, magnus::Error> {
+    let path = match hashmap.get("url") {
+        Some(url) => String::try_convert(*url)?,
+        None => {
+            let rb_error = magnus::Error::new(
+                magnus::exception::arg_error(),
+                "expected hashes with a url key",
+            );
+            return Err(rb_error);
+        }
+    };
+
+    let method = match hashmap.get("method") {
+        Some(method) => {
+            let name = name(*method)?;
+            Method::from_bytes(name.to_ascii_uppercase().as_bytes()).map_err(|_| {
+                magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    format!("invalid http method {}", name),
+                )
+            })?
+        }
+        None => Method::GET,
+    };
+
+    let mut query: Vec<(String, String)> = match hashmap.get("query") {
+        Some(rb_query) => RHash::try_convert(*rb_query)?
+            .to_hash_map::<String, Value>()?
+            .into_iter()
+            .map(|(name, value)| Ok((name, value.funcall::<_, _, String>("to_s", ())?)))
+            .collect::<Result<_, magnus::Error>>()?,
+        None => Vec::new(),
+    };
+    // ruby hashes come over unordered, sorting keeps urls and cache keys the same every time
+    query.sort();
+
+    let body = match hashmap.get("body") {
+        Some(body) if body.is_nil() => String::new(),
+        Some(body) => match RString::from_value(*body) {
+            Some(rb_string) => rb_string.to_string()?,
+            None => body.funcall::<_, _, String>("to_json", ())?,
+        },
+        None => String::new(),
+    };
+
+    let headers = match hashmap.get("headers") {
+        Some(rb_headers) => header_map(RHash::try_convert(*rb_headers)?)?,
+        None => HeaderMap::new(),
+    };
+
+    let url = match (reqwest::Url::parse(&path), base_url) {
+        (Ok(url), _) => Ok(url),
+        (Err(_), Some(base_url)) => reqwest::Url::parse(&join_url(base_url, &path)),
+        (Err(err), None) => Err(err),
+    };
+    let url = url.map_err(|err| {
+        magnus::Error::new(
+            magnus::exception::arg_error(),
+            format!("invalid url {}: {}, paths need a base_url", path, err),
+        )
+    })?;
+
+    let mut path = url.path().to_string();
+    if let Some(url_query) = url.query() {
+        path = format!("{}?{}", path, url_query);
+    }
+
+    Ok(Request {
+        method,
+        base_url: Some(url.origin().ascii_serialization()),
+        path,
+        query,
+        body,
+        headers,
+    })
+}
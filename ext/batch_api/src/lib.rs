@@ -1,16 +1,170 @@
+mod batch;
+mod gvl;
+mod http;
+mod options;
+mod osrm;
 mod vroom;
 mod zipcode_verification;
 
-use magnus::{class, function, method, prelude::*, Object, Ruby};
+use magnus::{class, exception, function, method, prelude::*, Object, Ruby};
 
 #[magnus::init]
 fn init(ruby: &Ruby) -> Result<(), magnus::Error> {
     let module = ruby.define_module("BatchApi")?;
+    let error = module.define_error("Error", exception::standard_error())?;
     // Vroom stuff
     let vroom = module.define_module("Vroom")?;
+
+    // raised for vroom's error codes when a batch asks for it
+    let vroom_error = vroom.define_error("Error", error)?;
+    vroom.define_error("InternalError", vroom_error)?;
+    vroom.define_error("InputError", vroom_error)?;
+    vroom.define_error("RoutingError", vroom_error)?;
+    // replaying a cassette without a recording for the request
+    vroom.define_error("CassetteError", error)?;
+
     vroom.define_module_function(
         "batch_send_api_requests",
-        function!(vroom::api::rb_batch_send_vroom_requests, 1),
+        function!(vroom::api::rb_batch_send_vroom_requests, -1),
+    )?;
+
+    // json http batches to anything else, sent the same way as vroom's
+    let http = module.define_module("Http")?;
+
+    http.define_module_function("batch", function!(http::rb_batch, -1))?;
+
+    let vroom_client = vroom.define_class("Client", class::object())?;
+
+    vroom_client.define_singleton_method("new", function!(vroom::client::Client::rb_new, -1))?;
+
+    vroom_client.define_method(
+        "batch_send",
+        method!(vroom::client::Client::rb_batch_send, -1),
+    )?;
+
+    vroom_client.define_method("auth=", method!(vroom::client::Client::rb_set_auth, 1))?;
+    vroom_client.define_method("circuits", method!(vroom::client::Client::rb_circuits, 0))?;
+
+    let vroom_cancellation_token = vroom.define_class("CancellationToken", class::object())?;
+
+    vroom_cancellation_token.define_singleton_method(
+        "new",
+        function!(batch::cancellation::CancellationToken::rb_new, 0),
+    )?;
+
+    vroom_cancellation_token.define_method(
+        "cancel",
+        method!(batch::cancellation::CancellationToken::rb_cancel, 0),
+    )?;
+
+    vroom_cancellation_token.define_method(
+        "cancelled?",
+        method!(batch::cancellation::CancellationToken::rb_is_cancelled, 0),
+    )?;
+
+    // a local stand-in for vroom to test batches against
+    let vroom_mock_server = vroom.define_class("MockServer", class::object())?;
+
+    vroom_mock_server.define_singleton_method(
+        "start",
+        function!(vroom::mock_server::MockServer::rb_start, -1),
+    )?;
+
+    vroom_mock_server.define_method("url", method!(vroom::mock_server::MockServer::rb_url, 0))?;
+
+    vroom_mock_server.define_method("port", method!(vroom::mock_server::MockServer::rb_port, 0))?;
+
+    vroom_mock_server.define_method(
+        "enqueue",
+        method!(vroom::mock_server::MockServer::rb_enqueue, 1),
+    )?;
+
+    vroom_mock_server.define_method("on", method!(vroom::mock_server::MockServer::rb_on, 2))?;
+
+    vroom_mock_server.define_method(
+        "requests",
+        method!(vroom::mock_server::MockServer::rb_requests, 0),
+    )?;
+
+    vroom_mock_server.define_method(
+        "request_lines",
+        method!(vroom::mock_server::MockServer::rb_request_lines, 0),
+    )?;
+
+    vroom_mock_server.define_method(
+        "request_headers",
+        method!(vroom::mock_server::MockServer::rb_request_headers, 0),
+    )?;
+
+    vroom_mock_server.define_method("stop", method!(vroom::mock_server::MockServer::rb_stop, 0))?;
+
+    let vroom_problem = vroom.define_class("Problem", class::object())?;
+
+    vroom_problem.define_singleton_method(
+        "new",
+        function!(vroom::problem::builder::MutProblem::rb_new, 0),
+    )?;
+
+    vroom_problem.define_method(
+        "add_vehicle",
+        method!(vroom::problem::builder::MutProblem::rb_add_vehicle, 1),
+    )?;
+
+    vroom_problem.define_method(
+        "add_job",
+        method!(vroom::problem::builder::MutProblem::rb_add_job, 1),
+    )?;
+
+    vroom_problem.define_method(
+        "add_shipment",
+        method!(vroom::problem::builder::MutProblem::rb_add_shipment, 1),
+    )?;
+
+    vroom_problem.define_method(
+        "add_matrices",
+        method!(vroom::problem::builder::MutProblem::rb_add_matrices, 2),
+    )?;
+
+    vroom_problem.define_method(
+        "geometry=",
+        method!(vroom::problem::builder::MutProblem::rb_set_geometry, 1),
+    )?;
+
+    // to_str lets a problem be passed straight in as a request body, both validate
+    for name in ["to_json", "to_str"] {
+        vroom_problem.define_method(
+            name,
+            method!(vroom::problem::builder::MutProblem::rb_to_json, 0),
+        )?;
+    }
+
+    vroom_problem.define_method(
+        "to_s",
+        method!(vroom::problem::builder::MutProblem::rb_to_s, 0),
+    )?;
+
+    vroom_problem.define_method(
+        "inspect",
+        method!(vroom::problem::builder::MutProblem::rb_inspect, 0),
+    )?;
+
+    // osrm, the routing engine vroom uses
+    let osrm = module.define_module("Osrm")?;
+
+    // raised when add_matrices can't get a table from osrm
+    osrm.define_error("Error", error)?;
+
+    osrm.define_module_function("batch", function!(osrm::api::rb_batch, -1))?;
+
+    let osrm_client = osrm.define_class("Client", class::object())?;
+
+    osrm_client.define_singleton_method("new", function!(osrm::client::Client::rb_new, -1))?;
+
+    osrm_client.define_method("batch", method!(osrm::client::Client::rb_batch, -1))?;
+
+    osrm_client.define_method(
+        "add_matrices",
+        method!(osrm::client::Client::rb_add_matrices, -1),
     )?;
 
     // KMZ / KML utilities
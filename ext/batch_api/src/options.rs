@@ -0,0 +1,131 @@
+// Helpers for reading ruby option hashes, shared by batches, vroom problems and osrm requests
+
+use std::time::Duration;
+
+use magnus::{prelude::*, r_hash::ForEach, RHash, Symbol, TryConvert, Value};
+
+/// `[lon, lat]`
+pub type Location = [f64; 2];
+
+/// Looks up an option that names something, as a symbol or a string
+pub fn fetch_name(rb_hash: RHash, key: &str) -> Result<Option<String>, magnus::Error> {
+    match fetch::<Value>(rb_hash, key)? {
+        Some(value) => Ok(Some(name(value)?)),
+        None => Ok(None),
+    }
+}
+
+/// A name given as a symbol or a string
+pub fn name(value: Value) -> Result<String, magnus::Error> {
+    match Symbol::from_value(value) {
+        Some(symbol) => Ok(symbol.name()?.into_owned()),
+        None => String::try_convert(value),
+    }
+}
+
+/// Looks up a timeout given in seconds, integers and floats are both fine
+pub fn fetch_duration(rb_hash: RHash, key: &str) -> Result<Option<Duration>, magnus::Error> {
+    match fetch::<f64>(rb_hash, key)? {
+        Some(seconds) if seconds > 0.0 && seconds.is_finite() => {
+            Ok(Some(Duration::from_secs_f64(seconds)))
+        }
+        Some(_) => {
+            let rb_error = magnus::Error::new(
+                magnus::exception::arg_error(),
+                format!("{} must be a positive number of seconds", key),
+            );
+            Err(rb_error)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Looks up an option by symbol key first then string key,
+/// so both `{ max_concurrency: 5 }` and `{ 'max_concurrency' => 5 }` work
+pub fn fetch<T: TryConvert>(rb_hash: RHash, key: &str) -> Result<Option<T>, magnus::Error> {
+    let value = match rb_hash.get(Symbol::new(key)) {
+        Some(value) => Some(value),
+        None => rb_hash.get(key),
+    };
+
+    match value {
+        Some(value) if !value.is_nil() => Ok(Some(T::try_convert(value)?)),
+        _ => Ok(None),
+    }
+}
+
+// A ruby hash describing one thing, e.g. a vroom job or an osrm request. Converts
+// each field to the type expected and names the field in the error when it can't
+pub struct Fields {
+    rb_hash: RHash,
+    what: &'static str,
+}
+
+impl Fields {
+    /// Unknown keys are rejected so typos fail here
+    /// rather than being quietly ignored by the server
+    pub fn new(rb_hash: RHash, what: &'static str, keys: &[&str]) -> Result<Self, magnus::Error> {
+        let fields = Fields { rb_hash, what };
+
+        rb_hash.foreach(|key: Value, _: Value| {
+            let key = match Symbol::from_value(key) {
+                Some(symbol) => symbol.name()?.into_owned(),
+                None => String::try_convert(key)?,
+            };
+            if !keys.contains(&key.as_str()) {
+                return Err(fields.error(&format!("has an unknown key {}", key)));
+            }
+            Ok(ForEach::Continue)
+        })?;
+
+        Ok(fields)
+    }
+
+    pub fn get<T: TryConvert>(&self, key: &str) -> Result<Option<T>, magnus::Error> {
+        fetch::<T>(self.rb_hash, key).map_err(|err| {
+            magnus::Error::new(
+                magnus::exception::type_error(),
+                format!("{} {} is the wrong type: {}", self.what, key, err),
+            )
+        })
+    }
+
+    pub fn required<T: TryConvert>(&self, key: &str) -> Result<T, magnus::Error> {
+        match self.get(key)? {
+            Some(value) => Ok(value),
+            None => Err(self.error(&format!("is missing {}", key))),
+        }
+    }
+
+    pub fn list<T: TryConvert>(&self, key: &str) -> Result<Vec<T>, magnus::Error> {
+        Ok(self.get::<Vec<T>>(key)?.unwrap_or_default())
+    }
+
+    pub fn location(&self, key: &str) -> Result<Option<Location>, magnus::Error> {
+        match self.get::<Vec<f64>>(key)? {
+            Some(coordinates) => match coordinates[..] {
+                [lon, lat] => Ok(Some([lon, lat])),
+                _ => Err(self.error(&format!("{} should be [lon, lat]", key))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// `[[lon, lat], ...]`
+    pub fn locations(&self, key: &str) -> Result<Vec<Location>, magnus::Error> {
+        self.list::<Vec<f64>>(key)?
+            .into_iter()
+            .map(|coordinates| match coordinates[..] {
+                [lon, lat] => Ok([lon, lat]),
+                _ => Err(self.error(&format!("{} should be [[lon, lat], ...]", key))),
+            })
+            .collect()
+    }
+
+    pub fn error(&self, message: &str) -> magnus::Error {
+        magnus::Error::new(
+            magnus::exception::arg_error(),
+            format!("{} {}", self.what, message),
+        )
+    }
+}
@@ -0,0 +1,142 @@
+// Sends osrm requests through the same batch client as vroom, so they get the
+// same runtime, ordering, concurrency limit, retries and error responses
+
+use magnus::{block, scan_args::scan_args, RArray, RHash, Value};
+
+use crate::batch::api::client_for;
+use crate::batch::client::Client;
+use crate::batch::options::{reject_vroom_only, BatchOptions};
+use crate::batch::request::Request;
+use crate::batch::response::Response;
+use crate::gvl;
+use crate::options::fetch_name;
+
+use super::request::OsrmRequest;
+use super::response::{into_rhash, OsrmResponse, Parsed};
+
+pub const DEFAULT_PROFILE: &str = "driving";
+
+/// `BatchApi::Osrm.batch(requests, base_url: ..., profile: 'driving', max_concurrency: ...)` through
+/// the default client, falling back to the OSRM_URL env var without a base url.
+/// Takes the same options as vroom batches apart from the vroom specific ones,
+/// and an optional block to stream responses to as they finish
+pub fn rb_batch(args: &[Value]) -> Result<Option<RArray>, magnus::Error> {
+    let args = scan_args::<(Vec<RHash>,), (Option<RHash>,), (), (), (), ()>(args)?;
+    let (rb_requests,) = args.required;
+    let (rb_options,) = args.optional;
+
+    let client = client_for(rb_options)?;
+    rb_send(&client, rb_requests, rb_options, DEFAULT_PROFILE)
+}
+
+/// Converts, sends and parses a batch of osrm requests for ruby. With a block each
+/// response is yielded as `|index, response|` as soon as it finishes and nil is returned,
+/// otherwise they're all returned in order once the batch is done
+pub fn rb_send(
+    client: &Client,
+    rb_requests: Vec<RHash>,
+    rb_options: Option<RHash>,
+    default_profile: &str,
+) -> Result<Option<RArray>, magnus::Error> {
+    reject_vroom_only(rb_options)?;
+    let options = BatchOptions::from_rhash(rb_options)?;
+
+    let profile = match rb_options {
+        Some(rb_options) => fetch_name(rb_options, "profile")?,
+        None => None,
+    };
+    let profile = profile.as_deref().unwrap_or(default_profile);
+
+    let mut osrm_requests: Vec<OsrmRequest> = Vec::with_capacity(rb_requests.len());
+    for rb_hash in rb_requests.into_iter() {
+        osrm_requests.push(OsrmRequest::from_rhash(rb_hash)?);
+    }
+
+    if block::block_given() {
+        let requests = into_requests(client, osrm_requests, &options, profile)?;
+        client.stream_send(requests, &options, |response| {
+            let index = response.sort_key;
+            let parsed = OsrmResponse::parse(&response);
+            let rb_hash = into_rhash(response, parsed)?;
+            block::yield_values::<(i32, RHash), Value>((index, rb_hash))?;
+            Ok(())
+        })?;
+        return Ok(None);
+    }
+
+    let rb_responses = send(client, osrm_requests, &options, profile)?
+        .into_iter()
+        .map(|(response, parsed)| into_rhash(response, parsed))
+        .collect::<Result<Vec<RHash>, magnus::Error>>()?;
+    Ok(Some(RArray::from_vec(rb_responses)))
+}
+
+/// Sends osrm requests and parses what comes back, in the order the requests were given.
+/// Parsing is done with the GVL released too, big tables take a while
+pub fn send(
+    client: &Client,
+    osrm_requests: Vec<OsrmRequest>,
+    options: &BatchOptions,
+    profile: &str,
+) -> Result<Vec<(Response, Parsed)>, magnus::Error> {
+    let requests = into_requests(client, osrm_requests, options, profile)?;
+    let responses = client.batch_send(requests, options)?;
+
+    gvl::without_gvl(
+        || {
+            responses
+                .into_iter()
+                .map(|response| {
+                    let parsed = OsrmResponse::parse(&response);
+                    (response, parsed)
+                })
+                .collect()
+        },
+        // nothing to interrupt, parsing finishes soon enough
+        || {},
+    )
+}
+
+// The base url works the same as for vroom requests, with OSRM_URL in place of VROOM_URL
+fn into_requests(
+    client: &Client,
+    osrm_requests: Vec<OsrmRequest>,
+    options: &BatchOptions,
+    profile: &str,
+) -> Result<Vec<Request>, magnus::Error> {
+    let (base_url, balanced) = client.base_url_for(options);
+    let base_url = match base_url {
+        Some(base_url) => Some(base_url.to_string()),
+        None if balanced => None,
+        None => Some(osrm_url_env_var()?),
+    };
+
+    // catch bad urls here rather than as connect errors later on
+    if let Some(base_url) = base_url.as_deref() {
+        if let Err(err) = reqwest::Url::parse(base_url) {
+            let rb_error = magnus::Error::new(
+                magnus::exception::arg_error(),
+                format!("invalid osrm url {}: {}", base_url, err),
+            );
+            return Err(rb_error);
+        }
+    }
+
+    Ok(osrm_requests
+        .into_iter()
+        .map(|osrm_request| osrm_request.into_request(base_url.clone(), profile))
+        .collect())
+}
+
+fn osrm_url_env_var() -> Result<String, magnus::Error> {
+    match std::env::var("OSRM_URL") {
+        Ok(osrm_url) => Ok(osrm_url),
+        Err(_) => {
+            let rb_error = magnus::Error::new(
+                magnus::exception::arg_error(),
+                "missing environment variable OSRM_URL",
+            );
+            Err(rb_error)
+        }
+    }
+}
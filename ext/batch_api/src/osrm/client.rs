@@ -0,0 +1,142 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use magnus::{scan_args::scan_args, RArray, RHash, TryConvert, Value};
+
+use crate::batch::cache::{CachePolicy, MemoryCache};
+use crate::batch::client::Client as BatchClient;
+use crate::batch::options::{reject_vroom_only, BatchOptions, ClientOptions};
+use crate::options::{fetch, fetch_name};
+use crate::vroom::problem::builder::MutProblem;
+use crate::vroom::problem::{Matrices, Problem};
+
+use super::api::{rb_send, DEFAULT_PROFILE};
+use super::matrix::{self, MatrixOptions};
+
+/// A long lived osrm client, keeping its runtime and connections between batches
+/// the same way as BatchApi::Vroom::Client. Create it after forking
+#[magnus::wrap(class = "BatchApi::Osrm::Client", free_immediately)]
+pub struct Client {
+    client: BatchClient,
+    // used for requests that don't give their own
+    profile: String,
+    // matrices from add_matrices, by location set
+    matrices: MemoryCache<Arc<Matrices>>,
+}
+
+impl Client {
+    // Functions for our ruby interface
+
+    /// `Client.new(base_url: ..., profile: 'driving', matrix_cache: { max_entries: 20, ttl: 3600 }, ...)`
+    /// with the same options as BatchApi::Vroom::Client
+    pub fn rb_new(args: &[Value]) -> Result<Self, magnus::Error> {
+        let args = scan_args::<(), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (rb_options,) = args.optional;
+
+        let mut profile = None;
+        let mut matrix_cache = matrix::default_cache_policy();
+        if let Some(rb_options) = rb_options {
+            profile = fetch_name(rb_options, "profile")?;
+            if let Some(rb_cache_hash) = fetch::<RHash>(rb_options, "matrix_cache")? {
+                matrix_cache = CachePolicy::from_rhash_or(rb_cache_hash, matrix_cache)?;
+            }
+        }
+
+        Ok(Client {
+            client: BatchClient::new(ClientOptions::from_rhash(rb_options)?)?,
+            profile: profile.unwrap_or_else(|| String::from(DEFAULT_PROFILE)),
+            matrices: MemoryCache::new(matrix_cache),
+        })
+    }
+
+    /// `client.batch(requests, max_concurrency: ..., profile: ...)`,
+    /// optionally with a block to stream responses as they finish
+    pub fn rb_batch(&self, args: &[Value]) -> Result<Option<RArray>, magnus::Error> {
+        let args = scan_args::<(Vec<RHash>,), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (rb_requests,) = args.required;
+        let (rb_options,) = args.optional;
+
+        rb_send(&self.client, rb_requests, rb_options, &self.profile)
+    }
+
+    /// `client.add_matrices(problems, profiles: { car: 'driving' }, max_table_size: 100, fallback_speed: 30, ...)`
+    /// plus the usual batch options for the table requests. Takes BatchApi::Vroom::Problem objects,
+    /// which are rewritten in place, or json strings, which come back rewritten with every other field kept
+    pub fn rb_add_matrices(&self, args: &[Value]) -> Result<RArray, magnus::Error> {
+        let args = scan_args::<(RArray,), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (rb_problems,) = args.required;
+        let (rb_options,) = args.optional;
+
+        reject_vroom_only(rb_options)?;
+        let batch_options = BatchOptions::from_rhash(rb_options)?;
+        let options = MatrixOptions::from_rhash(rb_options)?;
+
+        // json problems are kept as json too, to write the matrices back into
+        let mut problems: Vec<Problem> = Vec::with_capacity(rb_problems.len());
+        let mut jsons: Vec<Option<serde_json::Value>> = Vec::with_capacity(rb_problems.len());
+        for rb_problem in rb_problems.each() {
+            let rb_problem = rb_problem?;
+            match <&MutProblem>::try_convert(rb_problem) {
+                Ok(mut_problem) => {
+                    problems.push(mut_problem.problem().borrow().clone());
+                    jsons.push(None);
+                }
+                Err(_) => {
+                    let json = String::try_convert(rb_problem)?;
+                    let (problem, json) = serde_json::from_str::<serde_json::Value>(&json)
+                        .and_then(|json| {
+                            let problem = Problem::deserialize(&json)?;
+                            Ok((problem, json))
+                        })
+                        .map_err(|err| {
+                            magnus::Error::new(
+                                magnus::exception::arg_error(),
+                                format!("invalid vroom problem json: {}", err),
+                            )
+                        })?;
+                    problems.push(problem);
+                    jsons.push(Some(json));
+                }
+            }
+        }
+        // the ones add_matrices rewrites
+        let rewritten: Vec<bool> = problems
+            .iter()
+            .map(|problem| problem.matrices.is_empty())
+            .collect();
+
+        matrix::add_matrices(
+            &self.client,
+            &self.profile,
+            &self.matrices,
+            &mut problems,
+            &options,
+            &batch_options,
+        )?;
+
+        let rb_array = RArray::with_capacity(problems.len());
+        for (((rb_problem, problem), json), rewritten) in
+            rb_problems.each().zip(problems).zip(jsons).zip(rewritten)
+        {
+            let rb_problem = rb_problem?;
+            match json {
+                None => {
+                    if let Ok(mut_problem) = <&MutProblem>::try_convert(rb_problem) {
+                        *mut_problem.problem().borrow_mut() = problem;
+                    }
+                    rb_array.push(rb_problem)?;
+                }
+                // problems that already had matrices come back as they were given
+                Some(_) if !rewritten => rb_array.push(rb_problem)?,
+                Some(mut json) => {
+                    matrix::write_into_json(&problem, &mut json).map_err(|err| {
+                        magnus::Error::new(magnus::exception::runtime_error(), err)
+                    })?;
+                    rb_array.push(json.to_string())?;
+                }
+            }
+        }
+        Ok(rb_array)
+    }
+}
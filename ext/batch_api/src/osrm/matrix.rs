@@ -0,0 +1,540 @@
+// Duration and distance matrices for vroom problems from osrm's table service,
+// so vroom doesn't ask its routing engine for them again on every solve.
+// They're cached per location set, which lets what-if versions of a problem
+// with the same stops reuse them
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use magnus::{class, prelude::*, r_hash::ForEach, ExceptionClass, RHash, RModule, Value};
+
+use crate::batch::cache::{CachePolicy, MemoryCache};
+use crate::batch::client::Client;
+use crate::batch::options::BatchOptions;
+use crate::batch::response::Outcome;
+use crate::options::{fetch, name, Location};
+use crate::vroom::problem::{Matrices, Problem};
+
+use super::api;
+use super::request::{OsrmRequest, Service};
+use super::response::Parsed;
+
+// rows of durations or distances, null where osrm has no route
+type Cells = Vec<Vec<Option<f64>>>;
+// the same as vroom takes them
+type Square = Vec<Vec<u32>>;
+
+// the profile vroom gives vehicles without one
+const DEFAULT_VROOM_PROFILE: &str = "car";
+
+/// Matrices are big, so clients keep fewer of them than responses by default.
+/// `matrix_cache: { max_entries: 20, ttl: 3600 }`
+pub fn default_cache_policy() -> CachePolicy {
+    CachePolicy {
+        max_entries: 20,
+        ttl: Some(Duration::from_secs(3600)),
+    }
+}
+
+/// `profiles: { car: 'driving' }, max_table_size: 100, fallback_speed: 30`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatrixOptions {
+    // vroom profile to osrm profile, the rest use the client's profile
+    pub profiles: HashMap<String, String>,
+    // most sources and destinations in one table request, osrm-routed
+    // turns away tables bigger than its --max-table-size
+    pub max_table_size: usize,
+    // km/h for pairs osrm can't route between, which are an error otherwise
+    pub fallback_speed: Option<f64>,
+}
+
+impl Default for MatrixOptions {
+    fn default() -> Self {
+        MatrixOptions {
+            profiles: HashMap::new(),
+            max_table_size: 100,
+            fallback_speed: None,
+        }
+    }
+}
+
+impl MatrixOptions {
+    pub fn from_rhash(rb_hash: Option<RHash>) -> Result<Self, magnus::Error> {
+        let mut options = Self::default();
+
+        // no options hash passed, keep the defaults
+        let rb_hash = match rb_hash {
+            Some(rb_hash) => rb_hash,
+            None => return Ok(options),
+        };
+
+        if let Some(rb_profiles) = fetch::<RHash>(rb_hash, "profiles")? {
+            rb_profiles.foreach(|vroom_profile: Value, osrm_profile: Value| {
+                options
+                    .profiles
+                    .insert(name(vroom_profile)?, name(osrm_profile)?);
+                Ok(ForEach::Continue)
+            })?;
+        }
+
+        if let Some(max_table_size) = fetch::<usize>(rb_hash, "max_table_size")? {
+            if max_table_size == 0 {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "max_table_size must be greater than 0",
+                );
+                return Err(rb_error);
+            }
+            options.max_table_size = max_table_size;
+        }
+
+        options.fallback_speed = fetch::<f64>(rb_hash, "fallback_speed")?;
+
+        Ok(options)
+    }
+}
+
+// a problem's locations, and the cache key of its matrices for each vroom profile
+struct Plan {
+    locations: Vec<Location>,
+    profile_keys: Vec<(String, String)>,
+}
+
+// a table still to fetch from osrm, for one location set and profile
+struct Table {
+    key: String,
+    osrm_profile: String,
+    locations: Vec<Location>,
+}
+
+/// Rewrites each problem to use location indices into matrices from osrm, one for each
+/// vroom profile its vehicles use. Locations keep their coordinates for route geometry.
+/// Problems that already have matrices are left alone
+pub fn add_matrices(
+    client: &Client,
+    default_profile: &str,
+    cache: &MemoryCache<Arc<Matrices>>,
+    problems: &mut [Problem],
+    options: &MatrixOptions,
+    batch_options: &BatchOptions,
+) -> Result<(), magnus::Error> {
+    // None for problems left as they are
+    let mut plans: Vec<Option<Plan>> = Vec::new();
+    let mut found: HashMap<String, Arc<Matrices>> = HashMap::new();
+    let mut tables: Vec<Table> = Vec::new();
+
+    for problem in problems.iter() {
+        if !problem.matrices.is_empty() {
+            plans.push(None);
+            continue;
+        }
+
+        let locations = problem
+            .validate()
+            .and_then(|_| unique_locations(problem))
+            .map_err(|message| magnus::Error::new(magnus::exception::arg_error(), message))?;
+
+        let mut profile_keys = Vec::new();
+        for vroom_profile in vehicle_profiles(problem) {
+            let osrm_profile = options
+                .profiles
+                .get(&vroom_profile)
+                .map(String::as_str)
+                .unwrap_or(default_profile);
+            let key = cache_key(osrm_profile, &locations, options, batch_options);
+
+            // problems with the same stops share one fetch
+            if !found.contains_key(&key) && !tables.iter().any(|table| table.key == key) {
+                match cache.get(&key) {
+                    Some(matrices) => {
+                        found.insert(key.clone(), matrices);
+                    }
+                    None => tables.push(Table {
+                        key: key.clone(),
+                        osrm_profile: osrm_profile.to_string(),
+                        locations: locations.clone(),
+                    }),
+                }
+            }
+            profile_keys.push((vroom_profile, key));
+        }
+        plans.push(Some(Plan {
+            locations,
+            profile_keys,
+        }));
+    }
+
+    for (key, matrices) in fetch_tables(client, default_profile, tables, options, batch_options)? {
+        cache.put(&key, Arc::clone(&matrices));
+        found.insert(key, matrices);
+    }
+
+    for (problem, plan) in problems.iter_mut().zip(plans) {
+        if let Some(plan) = plan {
+            let matrices = plan
+                .profile_keys
+                .into_iter()
+                .map(|(vroom_profile, key)| (vroom_profile, found[&key].as_ref().clone()))
+                .collect();
+            use_matrices(problem, &plan.locations, matrices);
+        }
+    }
+    Ok(())
+}
+
+// Every table goes out in one batch, split into tiles of at most
+// max_table_size sources by max_table_size destinations
+fn fetch_tables(
+    client: &Client,
+    default_profile: &str,
+    tables: Vec<Table>,
+    options: &MatrixOptions,
+    batch_options: &BatchOptions,
+) -> Result<Vec<(String, Arc<Matrices>)>, magnus::Error> {
+    // all cached, nothing to send
+    if tables.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut tiles: Vec<(usize, Range<usize>, Range<usize>)> = Vec::new();
+    let mut requests: Vec<OsrmRequest> = Vec::new();
+
+    for (table_index, table) in tables.iter().enumerate() {
+        let blocks = blocks(table.locations.len(), options.max_table_size);
+        for rows in blocks.iter() {
+            for columns in blocks.iter() {
+                requests.push(tile_request(table, rows, columns, options));
+                tiles.push((table_index, rows.clone(), columns.clone()));
+            }
+        }
+    }
+
+    let mut matrices: Vec<(Square, Square)> = tables
+        .iter()
+        .map(|table| {
+            let size = table.locations.len();
+            (vec![vec![0; size]; size], vec![vec![0; size]; size])
+        })
+        .collect();
+
+    let responses = api::send(client, requests, batch_options, default_profile)?;
+    for ((table_index, rows, columns), (response, parsed)) in tiles.into_iter().zip(responses) {
+        let table = &tables[table_index];
+        let (durations, distances) = &mut matrices[table_index];
+
+        let filled =
+            tile_result(&response.outcome, parsed).and_then(|(tile_durations, tile_distances)| {
+                fill(
+                    durations,
+                    tile_durations,
+                    "durations",
+                    table,
+                    &rows,
+                    &columns,
+                )?;
+                fill(
+                    distances,
+                    tile_distances,
+                    "distances",
+                    table,
+                    &rows,
+                    &columns,
+                )
+            });
+        if let Err(message) = filled {
+            return Err(osrm_error(format!(
+                "couldn't get a {} table for {} locations: {}",
+                table.osrm_profile,
+                table.locations.len(),
+                message
+            )));
+        }
+    }
+
+    Ok(tables
+        .into_iter()
+        .zip(matrices)
+        .map(|(table, (durations, distances))| {
+            let matrices = Matrices {
+                durations: Some(durations),
+                distances: Some(distances),
+                costs: None,
+            };
+            (table.key, Arc::new(matrices))
+        })
+        .collect())
+}
+
+// A tile on the diagonal only needs its own locations,
+// the rest send their rows' locations then their columns'
+fn tile_request(
+    table: &Table,
+    rows: &Range<usize>,
+    columns: &Range<usize>,
+    options: &MatrixOptions,
+) -> OsrmRequest {
+    let mut request = if rows == columns {
+        OsrmRequest::table(table.locations[rows.clone()].to_vec())
+    } else {
+        let mut coordinates = table.locations[rows.clone()].to_vec();
+        coordinates.extend_from_slice(&table.locations[columns.clone()]);
+        let mut request = OsrmRequest::table(coordinates);
+        if let Service::Table(table_options) = &mut request.service {
+            table_options.sources = Some((0..rows.len()).collect());
+            table_options.destinations = Some((rows.len()..rows.len() + columns.len()).collect());
+        }
+        request
+    };
+
+    if let Service::Table(table_options) = &mut request.service {
+        table_options.fallback_speed = options.fallback_speed;
+    }
+    request.profile = Some(table.osrm_profile.clone());
+    request
+}
+
+// osrm's durations and distances for a tile, or why they're not there
+fn tile_result(
+    outcome: &Outcome,
+    parsed: Parsed,
+) -> Result<(Option<Cells>, Option<Cells>), String> {
+    match (outcome, parsed) {
+        (
+            Outcome::Error {
+                error_kind,
+                message,
+            },
+            _,
+        ) => Err(format!("{}: {}", error_kind.as_str(), message)),
+        (
+            Outcome::Http {
+                http_status_code, ..
+            },
+            None,
+        ) => Err(format!("http status {}", http_status_code)),
+        (_, Some(Err(message))) => Err(message),
+        (_, Some(Ok(osrm_response))) => match osrm_response.error() {
+            Some(message) => Err(message),
+            None => Ok((osrm_response.durations, osrm_response.distances)),
+        },
+    }
+}
+
+// Copies a tile osrm answered into its place in the full matrix, rounded to whole seconds or meters
+fn fill(
+    matrix: &mut [Vec<u32>],
+    tile: Option<Cells>,
+    what: &str,
+    table: &Table,
+    rows: &Range<usize>,
+    columns: &Range<usize>,
+) -> Result<(), String> {
+    let tile = tile.ok_or_else(|| format!("osrm left out the {}", what))?;
+    if tile.len() != rows.len() || tile.iter().any(|cells| cells.len() != columns.len()) {
+        return Err(format!("osrm sent {} of the wrong size", what));
+    }
+
+    for (row, cells) in rows.clone().zip(tile) {
+        for (column, cell) in columns.clone().zip(cells) {
+            match cell {
+                Some(value) => matrix[row][column] = value.round() as u32,
+                None => {
+                    return Err(format!(
+                        "no route from {:?} to {:?}, a fallback_speed fills these in",
+                        table.locations[row], table.locations[column]
+                    ))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Splits 0..size into ranges of at most max_size
+fn blocks(size: usize, max_size: usize) -> Vec<Range<usize>> {
+    (0..size)
+        .step_by(max_size)
+        .map(|start| start..(start + max_size).min(size))
+        .collect()
+}
+
+/// Every location a problem uses without repeats, in coordinate order so problems
+/// with the same stops get the same matrices whatever order they list them in
+fn unique_locations(problem: &Problem) -> Result<Vec<Location>, String> {
+    let mut locations: Vec<Location> = Vec::new();
+
+    for vehicle in problem.vehicles.iter() {
+        for (kind, location, index) in [
+            ("start", vehicle.start, vehicle.start_index),
+            ("end", vehicle.end, vehicle.end_index),
+        ] {
+            match (location, index) {
+                (Some(location), _) => locations.push(location),
+                // an index into the caller's own matrix would point somewhere else in ours
+                (None, Some(_)) => {
+                    return Err(format!(
+                        "vehicle {} {} needs coordinates for matrices",
+                        vehicle.id, kind
+                    ))
+                }
+                (None, None) => {}
+            }
+        }
+    }
+    for job in problem.jobs.iter() {
+        match job.location {
+            Some(location) => locations.push(location),
+            None => return Err(format!("job {} needs a location for matrices", job.id)),
+        }
+    }
+    for shipment in problem.shipments.iter() {
+        for (kind, step) in [
+            ("pickup", &shipment.pickup),
+            ("delivery", &shipment.delivery),
+        ] {
+            match step.location {
+                Some(location) => locations.push(location),
+                None => {
+                    return Err(format!(
+                        "shipment {} {} needs a location for matrices",
+                        kind, step.id
+                    ))
+                }
+            }
+        }
+    }
+
+    locations.sort_by(compare);
+    locations.dedup_by(|a, b| compare(a, b) == Ordering::Equal);
+    Ok(locations)
+}
+
+fn vehicle_profiles(problem: &Problem) -> BTreeSet<String> {
+    problem
+        .vehicles
+        .iter()
+        .map(|vehicle| {
+            vehicle
+                .profile
+                .clone()
+                .unwrap_or_else(|| String::from(DEFAULT_VROOM_PROFILE))
+        })
+        .collect()
+}
+
+// Points the problem's locations at their place in the location set,
+// vehicles without a start or end are left without one
+fn use_matrices(
+    problem: &mut Problem,
+    locations: &[Location],
+    matrices: BTreeMap<String, Matrices>,
+) {
+    let point = |location: &Option<Location>, index: &mut Option<usize>| {
+        let found = location.as_ref().and_then(|location| {
+            locations
+                .binary_search_by(|other| compare(other, location))
+                .ok()
+        });
+        if found.is_some() {
+            *index = found;
+        }
+    };
+
+    for vehicle in problem.vehicles.iter_mut() {
+        point(&vehicle.start, &mut vehicle.start_index);
+        point(&vehicle.end, &mut vehicle.end_index);
+    }
+    for job in problem.jobs.iter_mut() {
+        point(&job.location, &mut job.location_index);
+    }
+    for shipment in problem.shipments.iter_mut() {
+        for step in [&mut shipment.pickup, &mut shipment.delivery] {
+            point(&step.location, &mut step.location_index);
+        }
+    }
+    problem.matrices = matrices;
+}
+
+/// Writes a rewritten problem's location indices and matrices into the json it was read from,
+/// so the fields the problem model doesn't know about are kept as they were
+pub fn write_into_json(problem: &Problem, json: &mut serde_json::Value) -> Result<(), String> {
+    fn entries<'a>(
+        json: &'a mut serde_json::Value,
+        key: &str,
+    ) -> impl Iterator<Item = &'a mut serde_json::Value> {
+        json.get_mut(key)
+            .and_then(serde_json::Value::as_array_mut)
+            .into_iter()
+            .flatten()
+    }
+    fn set(json: &mut serde_json::Value, key: &str, index: Option<usize>) {
+        if let (Some(object), Some(index)) = (json.as_object_mut(), index) {
+            object.insert(key.to_string(), index.into());
+        }
+    }
+
+    for (vehicle, json) in problem.vehicles.iter().zip(entries(json, "vehicles")) {
+        set(json, "start_index", vehicle.start_index);
+        set(json, "end_index", vehicle.end_index);
+    }
+    for (job, json) in problem.jobs.iter().zip(entries(json, "jobs")) {
+        set(json, "location_index", job.location_index);
+    }
+    for (shipment, json) in problem.shipments.iter().zip(entries(json, "shipments")) {
+        for (step, key) in [
+            (&shipment.pickup, "pickup"),
+            (&shipment.delivery, "delivery"),
+        ] {
+            if let Some(json) = json.get_mut(key) {
+                set(json, "location_index", step.location_index);
+            }
+        }
+    }
+
+    let matrices = serde_json::to_value(&problem.matrices).map_err(|err| err.to_string())?;
+    if let Some(object) = json.as_object_mut() {
+        object.insert(String::from("matrices"), matrices);
+    }
+    Ok(())
+}
+
+// total_cmp so every coordinate has its place, even -0.0 and NaN
+fn compare(a: &Location, b: &Location) -> Ordering {
+    a[0].total_cmp(&b[0]).then(a[1].total_cmp(&b[1]))
+}
+
+// Anything that changes the matrices osrm would send back
+fn cache_key(
+    osrm_profile: &str,
+    locations: &[Location],
+    options: &MatrixOptions,
+    batch_options: &BatchOptions,
+) -> String {
+    let coordinates: Vec<String> = locations
+        .iter()
+        .map(|[lon, lat]| format!("{},{}", lon, lat))
+        .collect();
+    format!(
+        "{} {} {:?} {}",
+        batch_options.base_url.as_deref().unwrap_or_default(),
+        osrm_profile,
+        options.fallback_speed,
+        coordinates.join(";")
+    )
+}
+
+/// BatchApi::Osrm::Error, for matrices osrm couldn't give
+fn osrm_error(message: String) -> magnus::Error {
+    let exception_class = || -> Result<ExceptionClass, magnus::Error> {
+        let batch_api: RModule = class::object().const_get("BatchApi")?;
+        let osrm: RModule = batch_api.const_get("Osrm")?;
+        osrm.const_get("Error")
+    };
+    match exception_class() {
+        Ok(exception_class) => magnus::Error::new(exception_class, message),
+        Err(err) => err,
+    }
+}
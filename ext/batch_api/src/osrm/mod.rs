@@ -0,0 +1,7 @@
+// OSRM, the routing engine behind vroom, for its table, route, nearest, match and trip services
+
+pub mod api;
+pub mod client;
+pub mod matrix;
+pub(crate) mod request;
+pub(crate) mod response;
@@ -0,0 +1,505 @@
+// Rust types for osrm's services, see
+// https://project-osrm.org/docs/v5.24.0/api/#services
+// Options that aren't set are left out of the query so osrm applies its defaults
+
+use magnus::{prelude::*, RArray, RHash, Value};
+use reqwest::header::HeaderMap;
+use reqwest::Method;
+
+use crate::batch::auth::header_map;
+use crate::batch::request::Request;
+use crate::options::{fetch, Fields, Location};
+
+// keys every service takes
+const GENERAL_KEYS: [&str; 7] = [
+    "service",
+    "profile",
+    "coordinates",
+    "radiuses",
+    "exclude",
+    "generate_hints",
+    "headers",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Service {
+    Table(TableOptions),
+    Route(RouteOptions),
+    Nearest { number: Option<u32> },
+    Match(MatchOptions),
+    Trip(TripOptions),
+}
+
+impl Service {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Service::Table(_) => "table",
+            Service::Route(_) => "route",
+            Service::Nearest { .. } => "nearest",
+            Service::Match(_) => "match",
+            Service::Trip(_) => "trip",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TableOptions {
+    // indices into the coordinates, None for all of them
+    pub sources: Option<Vec<usize>>,
+    pub destinations: Option<Vec<usize>>,
+    // duration and/or distance, osrm only gives durations by default
+    pub annotations: Vec<String>,
+    // km/h used for pairs osrm can't find a route between, rather than null
+    pub fallback_speed: Option<f64>,
+    pub scale_factor: Option<f64>,
+}
+
+/// How much of the route comes back, for route, match and trip
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GeometryOptions {
+    pub steps: Option<bool>,
+    // true, false or a comma separated list like duration,distance
+    pub annotations: Option<String>,
+    // polyline, polyline6 or geojson
+    pub geometries: Option<String>,
+    // simplified, full or false
+    pub overview: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RouteOptions {
+    pub geometry: GeometryOptions,
+    pub alternatives: Option<u32>,
+    pub continue_straight: Option<bool>,
+    // indices of the coordinates that start and end legs, the rest are passed through
+    pub waypoints: Option<Vec<usize>>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MatchOptions {
+    pub geometry: GeometryOptions,
+    // unix seconds for each coordinate
+    pub timestamps: Option<Vec<u64>>,
+    // split or ignore
+    pub gaps: Option<String>,
+    pub tidy: Option<bool>,
+    pub waypoints: Option<Vec<usize>>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TripOptions {
+    pub geometry: GeometryOptions,
+    pub roundtrip: Option<bool>,
+    // any or first
+    pub source: Option<String>,
+    // any or last
+    pub destination: Option<String>,
+}
+
+/// A request to one of osrm's services
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsrmRequest {
+    pub service: Service,
+    // None uses the batch's profile
+    pub profile: Option<String>,
+    pub coordinates: Vec<Location>,
+    // how far in meters each coordinate can be snapped to the road, None for no limit
+    pub radiuses: Option<Vec<Option<f64>>>,
+    // road classes to avoid, e.g. toll or motorway
+    pub exclude: Vec<String>,
+    pub generate_hints: Option<bool>,
+    pub headers: HeaderMap,
+}
+
+impl OsrmRequest {
+    pub fn new(service: Service, coordinates: Vec<Location>) -> Self {
+        OsrmRequest {
+            service,
+            profile: None,
+            coordinates,
+            radiuses: None,
+            exclude: Vec::new(),
+            generate_hints: None,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    /// A table of durations and distances between every pair of coordinates
+    pub fn table(coordinates: Vec<Location>) -> Self {
+        let options = TableOptions {
+            annotations: vec![String::from("duration"), String::from("distance")],
+            ..Default::default()
+        };
+        Self::new(Service::Table(options), coordinates)
+    }
+
+    /// `{ service: :table, coordinates: [[lon, lat], ...], sources: [0], annotations: %w[duration distance] }`,
+    /// unknown keys for the service are rejected
+    pub fn from_rhash(rb_hash: RHash) -> Result<Self, magnus::Error> {
+        let service_name = match fetch::<Value>(rb_hash, "service")? {
+            Some(service) => service.funcall::<_, _, String>("to_s", ())?,
+            None => {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "osrm requests need a service",
+                );
+                return Err(rb_error);
+            }
+        };
+
+        let service_keys: &[&str] = match service_name.as_str() {
+            "table" => &[
+                "sources",
+                "destinations",
+                "annotations",
+                "fallback_speed",
+                "scale_factor",
+            ],
+            "route" => &[
+                "steps",
+                "annotations",
+                "geometries",
+                "overview",
+                "alternatives",
+                "continue_straight",
+                "waypoints",
+            ],
+            "nearest" => &["number"],
+            "match" => &[
+                "steps",
+                "annotations",
+                "geometries",
+                "overview",
+                "timestamps",
+                "gaps",
+                "tidy",
+                "waypoints",
+            ],
+            "trip" => &[
+                "steps",
+                "annotations",
+                "geometries",
+                "overview",
+                "roundtrip",
+                "source",
+                "destination",
+            ],
+            _ => {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    format!(
+                        "unknown osrm service {}, expected table, route, nearest, match or trip",
+                        service_name
+                    ),
+                );
+                return Err(rb_error);
+            }
+        };
+        let keys: Vec<&str> = GENERAL_KEYS.iter().chain(service_keys).copied().collect();
+        let fields = Fields::new(rb_hash, "osrm request", &keys)?;
+
+        let service = match service_name.as_str() {
+            "table" => Service::Table(TableOptions {
+                sources: fields.get("sources")?,
+                destinations: fields.get("destinations")?,
+                annotations: choices(&fields, "annotations", &["duration", "distance"])?,
+                fallback_speed: fields.get("fallback_speed")?,
+                scale_factor: fields.get("scale_factor")?,
+            }),
+            "route" => Service::Route(RouteOptions {
+                geometry: geometry_options(&fields)?,
+                alternatives: fields.get("alternatives")?,
+                continue_straight: fields.get("continue_straight")?,
+                waypoints: fields.get("waypoints")?,
+            }),
+            "nearest" => Service::Nearest {
+                number: fields.get("number")?,
+            },
+            "match" => Service::Match(MatchOptions {
+                geometry: geometry_options(&fields)?,
+                timestamps: fields.get("timestamps")?,
+                gaps: choice(&fields, "gaps", &["split", "ignore"])?,
+                tidy: fields.get("tidy")?,
+                waypoints: fields.get("waypoints")?,
+            }),
+            _ => Service::Trip(TripOptions {
+                geometry: geometry_options(&fields)?,
+                roundtrip: fields.get("roundtrip")?,
+                source: choice(&fields, "source", &["any", "first"])?,
+                destination: choice(&fields, "destination", &["any", "last"])?,
+            }),
+        };
+
+        let headers = match fields.get::<RHash>("headers")? {
+            Some(rb_headers) => header_map(rb_headers)?,
+            None => HeaderMap::new(),
+        };
+
+        let request = OsrmRequest {
+            service,
+            profile: fields.get("profile")?,
+            coordinates: fields.locations("coordinates")?,
+            radiuses: fields.get("radiuses")?,
+            exclude: fields.list("exclude")?,
+            generate_hints: fields.get("generate_hints")?,
+            headers,
+        };
+        request
+            .validate()
+            .map_err(|message| fields.error(&message))?;
+        Ok(request)
+    }
+
+    /// Catches what osrm would turn away with InvalidQuery before it's sent
+    pub fn validate(&self) -> Result<(), String> {
+        let count = self.coordinates.len();
+        let (min, max) = match self.service {
+            Service::Nearest { .. } => (1, 1),
+            Service::Table(_) => (1, usize::MAX),
+            _ => (2, usize::MAX),
+        };
+        if count < min || count > max {
+            return Err(format!(
+                "for {} needs {} coordinates, got {}",
+                self.service.name(),
+                if min == max {
+                    String::from("exactly 1")
+                } else {
+                    format!("at least {}", min)
+                },
+                count
+            ));
+        }
+
+        let indices = match &self.service {
+            Service::Table(table) => [table.sources.as_ref(), table.destinations.as_ref()],
+            Service::Route(route) => [route.waypoints.as_ref(), None],
+            Service::Match(matching) => [matching.waypoints.as_ref(), None],
+            _ => [None, None],
+        };
+        if indices
+            .iter()
+            .flatten()
+            .flat_map(|list| list.iter())
+            .any(|index| *index >= count)
+        {
+            return Err(String::from("refers to a coordinate that isn't there"));
+        }
+
+        let per_coordinate = [
+            ("radiuses", self.radiuses.as_ref().map(Vec::len)),
+            (
+                "timestamps",
+                match &self.service {
+                    Service::Match(matching) => matching.timestamps.as_ref().map(Vec::len),
+                    _ => None,
+                },
+            ),
+        ];
+        for (key, len) in per_coordinate {
+            if len.is_some_and(|len| len != count) {
+                return Err(format!("needs one of its {} for each coordinate", key));
+            }
+        }
+        Ok(())
+    }
+
+    /// e.g. `/table/v1/driving/-1.08,53.96;-1.54,53.8?annotations=duration,distance`.
+    /// The query is written out here rather than left to reqwest, which would escape the ; and , osrm wants
+    pub fn path(&self, default_profile: &str) -> String {
+        let coordinates: Vec<String> = self
+            .coordinates
+            .iter()
+            .map(|[lon, lat]| format!("{},{}", lon, lat))
+            .collect();
+        let mut path = format!(
+            "/{}/v1/{}/{}",
+            self.service.name(),
+            self.profile.as_deref().unwrap_or(default_profile),
+            coordinates.join(";")
+        );
+
+        let query: Vec<String> = self
+            .query()
+            .into_iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        if !query.is_empty() {
+            path.push('?');
+            path.push_str(&query.join("&"));
+        }
+        path
+    }
+
+    /// Sent as a GET to the base url, or the client's balanced endpoints when it's None
+    pub fn into_request(self, base_url: Option<String>, default_profile: &str) -> Request {
+        Request {
+            method: Method::GET,
+            base_url,
+            path: self.path(default_profile),
+            query: Vec::new(),
+            body: String::new(),
+            headers: self.headers,
+        }
+    }
+
+    fn query(&self) -> Vec<(&'static str, String)> {
+        let mut query: Vec<(&'static str, String)> = Vec::new();
+        let mut push = |name: &'static str, value: Option<String>| {
+            if let Some(value) = value {
+                query.push((name, value));
+            }
+        };
+
+        push(
+            "radiuses",
+            self.radiuses.as_ref().map(|radiuses| {
+                join(radiuses.iter().map(|radius| match radius {
+                    Some(radius) => radius.to_string(),
+                    None => String::from("unlimited"),
+                }))
+            }),
+        );
+        push(
+            "exclude",
+            Some(self.exclude.join(",")).filter(|exclude| !exclude.is_empty()),
+        );
+        push("generate_hints", self.generate_hints.map(|g| g.to_string()));
+
+        let geometry = match &self.service {
+            Service::Table(table) => {
+                push("sources", table.sources.as_ref().map(|s| join(s.iter())));
+                push(
+                    "destinations",
+                    table.destinations.as_ref().map(|d| join(d.iter())),
+                );
+                push(
+                    "annotations",
+                    Some(table.annotations.join(",")).filter(|a| !a.is_empty()),
+                );
+                push(
+                    "fallback_speed",
+                    table.fallback_speed.map(|f| f.to_string()),
+                );
+                push("scale_factor", table.scale_factor.map(|s| s.to_string()));
+                None
+            }
+            Service::Route(route) => {
+                push("alternatives", route.alternatives.map(|a| a.to_string()));
+                push(
+                    "continue_straight",
+                    route.continue_straight.map(|c| c.to_string()),
+                );
+                push(
+                    "waypoints",
+                    route.waypoints.as_ref().map(|w| join(w.iter())),
+                );
+                Some(&route.geometry)
+            }
+            Service::Nearest { number } => {
+                push("number", number.map(|n| n.to_string()));
+                None
+            }
+            Service::Match(matching) => {
+                push(
+                    "timestamps",
+                    matching.timestamps.as_ref().map(|t| join(t.iter())),
+                );
+                push("gaps", matching.gaps.clone());
+                push("tidy", matching.tidy.map(|t| t.to_string()));
+                push(
+                    "waypoints",
+                    matching.waypoints.as_ref().map(|w| join(w.iter())),
+                );
+                Some(&matching.geometry)
+            }
+            Service::Trip(trip) => {
+                push("roundtrip", trip.roundtrip.map(|r| r.to_string()));
+                push("source", trip.source.clone());
+                push("destination", trip.destination.clone());
+                Some(&trip.geometry)
+            }
+        };
+
+        if let Some(geometry) = geometry {
+            push("steps", geometry.steps.map(|s| s.to_string()));
+            push("annotations", geometry.annotations.clone());
+            push("geometries", geometry.geometries.clone());
+            push("overview", geometry.overview.clone());
+        }
+        query
+    }
+}
+
+fn geometry_options(fields: &Fields) -> Result<GeometryOptions, magnus::Error> {
+    // true, false or a list of what to annotate. Any ruby value converts to a bool,
+    // so lists have to be picked out first
+    let annotations = match fields.get::<Value>("annotations")? {
+        Some(value) if RArray::from_value(value).is_some() => Some(
+            choices(
+                fields,
+                "annotations",
+                &[
+                    "duration",
+                    "nodes",
+                    "distance",
+                    "weight",
+                    "datasources",
+                    "speed",
+                ],
+            )?
+            .join(","),
+        )
+        .filter(|annotations| !annotations.is_empty()),
+        Some(_) => fields.get::<bool>("annotations")?.map(|a| a.to_string()),
+        None => None,
+    };
+
+    Ok(GeometryOptions {
+        steps: fields.get("steps")?,
+        annotations,
+        geometries: choice(fields, "geometries", &["polyline", "polyline6", "geojson"])?,
+        overview: choice(fields, "overview", &["simplified", "full", "false"])?,
+    })
+}
+
+// one of a few words, as a symbol, string or boolean
+fn choice(fields: &Fields, key: &str, allowed: &[&str]) -> Result<Option<String>, magnus::Error> {
+    match fields.get::<Value>(key)? {
+        Some(value) => {
+            let value = value.funcall::<_, _, String>("to_s", ())?;
+            if !allowed.contains(&value.as_str()) {
+                return Err(fields.error(&format!(
+                    "{} should be one of {}",
+                    key,
+                    allowed.join(", ")
+                )));
+            }
+            Ok(Some(value))
+        }
+        None => Ok(None),
+    }
+}
+
+// a list of a few words
+fn choices(fields: &Fields, key: &str, allowed: &[&str]) -> Result<Vec<String>, magnus::Error> {
+    let values: Vec<String> = fields
+        .list::<Value>(key)?
+        .into_iter()
+        .map(|value| value.funcall::<_, _, String>("to_s", ()))
+        .collect::<Result<_, _>>()?;
+    if values
+        .iter()
+        .any(|value| !allowed.contains(&value.as_str()))
+    {
+        return Err(fields.error(&format!("{} should be some of {}", key, allowed.join(", "))));
+    }
+    Ok(values)
+}
+
+fn join<T: ToString>(items: impl Iterator<Item = T>) -> String {
+    items
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(";")
+}
@@ -0,0 +1,171 @@
+// Rust types for osrm's responses, see
+// https://project-osrm.org/docs/v5.24.0/api/#responses
+// Each service fills in its own fields, the rest are left empty
+
+use magnus::{RHash, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::batch::response::{json_into_ruby, Outcome, Response};
+use crate::options::Location;
+
+/// What came back from osrm. None for transport errors and error statuses without osrm's json
+pub type Parsed = Option<Result<OsrmResponse, String>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OsrmResponse {
+    // Ok, or an error code like NoRoute or InvalidQuery along with a message
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    // table, rows for sources and columns for destinations. null where there's no route
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub durations: Option<Vec<Vec<Option<f64>>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distances: Option<Vec<Vec<Option<f64>>>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<Waypoint>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub destinations: Vec<Waypoint>,
+    // [row, column] of cells estimated with the fallback speed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback_speed_cells: Option<Vec<[usize; 2]>>,
+    // route
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<Route>,
+    // match, with a null tracepoint for each coordinate that couldn't be matched
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub matchings: Vec<Route>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tracepoints: Vec<Option<Waypoint>>,
+    // trip
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trips: Vec<Route>,
+    // route, nearest and trip
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub waypoints: Vec<Waypoint>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Route {
+    // meters and seconds
+    pub distance: f64,
+    pub duration: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight_name: Option<String>,
+    // an encoded polyline or a geojson linestring, depending on what was asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geometry: Option<serde_json::Value>,
+    #[serde(default)]
+    pub legs: Vec<RouteLeg>,
+    // match only, how sure osrm is of the matching from 0 to 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RouteLeg {
+    pub distance: f64,
+    pub duration: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    // turn by turn instructions, passed through as osrm gives them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotation: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Waypoint {
+    // the coordinate snapped to the road
+    pub location: Location,
+    // street name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // meters from the coordinate asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+    // nearest only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<Vec<u64>>,
+    // which trip or matching it's part of and where, for trip and match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub waypoint_index: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trips_index: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matchings_index: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alternatives_count: Option<usize>,
+}
+
+impl OsrmResponse {
+    /// Parses the body of a response osrm answered. Error statuses with a body
+    /// that isn't osrm's (e.g. a proxy's 502 page) give None so the status speaks for itself
+    pub fn parse(response: &Response) -> Parsed {
+        let (http_status_code, body) = match &response.outcome {
+            Outcome::Http {
+                http_status_code,
+                body,
+                ..
+            } => (*http_status_code, body),
+            Outcome::Error { .. } => return None,
+        };
+
+        match serde_json::from_str::<OsrmResponse>(body) {
+            Ok(osrm_response) => Some(Ok(osrm_response)),
+            Err(_) if !(200..300).contains(&http_status_code) => None,
+            Err(err) => Some(Err(format!("osrm response is not valid: {}", err))),
+        }
+    }
+
+    /// osrm's error code and message, None when it's Ok
+    pub fn error(&self) -> Option<String> {
+        if self.code == "Ok" {
+            return None;
+        }
+        Some(match &self.message {
+            Some(message) => format!("{}: {}", self.code, message),
+            None => self.code.clone(),
+        })
+    }
+
+    /// The response as nested ruby hashes and arrays with string keys, like JSON.parse gives
+    pub fn into_ruby(self) -> Result<Value, magnus::Error> {
+        let json = serde_json::to_value(self).map_err(|err| {
+            magnus::Error::new(magnus::exception::runtime_error(), err.to_string())
+        })?;
+        json_into_ruby(json)
+    }
+}
+
+/// The usual response hash with osrm's `'code'` and the parsed `'result'`.
+/// osrm errors come back as `'error_kind' => 'osrm'`, bodies that aren't osrm's as 'invalid_response'
+pub fn into_rhash(response: Response, parsed: Parsed) -> Result<RHash, magnus::Error> {
+    let rb_hash = response.into_rhash()?;
+
+    match parsed {
+        Some(Ok(osrm_response)) => {
+            rb_hash.aset("code", osrm_response.code.as_str())?;
+            match osrm_response.error() {
+                Some(message) => {
+                    rb_hash.aset("error_kind", "osrm")?;
+                    rb_hash.aset("error_message", message)?;
+                }
+                None => rb_hash.aset("result", osrm_response.into_ruby()?)?,
+            }
+        }
+        Some(Err(message)) => {
+            rb_hash.aset("error_kind", "invalid_response")?;
+            rb_hash.aset("error_message", message)?;
+        }
+        None => {}
+    }
+    Ok(rb_hash)
+}
@@ -1,81 +1,142 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use magnus::{block, scan_args::scan_args, RArray, RHash, Value};
 
-use super::request::Request;
-use super::response::Response;
+use crate::batch::api::client_for;
+use crate::batch::client::{Client, RbArrayOfHashes};
+use crate::batch::request::Request;
+use crate::batch::response::Response;
+use crate::gvl;
 
-// magnus converts the ruby hash to rust types for us
-type RbArrayOfHashes = Vec<HashMap<String, String>>;
+use super::cassette::CassetteMode;
+use super::options::VroomOptions;
+use super::request;
+use super::response::VroomResponse;
 
-/// Sends vroom api requests async using single threaded tokio runtime
+/// Sends vroom api requests async through a default client,
+/// with the GVL released while waiting on the network so other ruby threads keep running
 /// Raises ruby exceptions if the arguments are not hashes and if the VROOM_URL env var is not present
-pub fn rb_batch_send_vroom_requests(
+/// Takes an optional options hash as the second argument, e.g. `{ max_concurrency: 10 }`
+/// and an optional block to stream responses to as they finish
+pub fn rb_batch_send_vroom_requests(args: &[Value]) -> Result<Option<RArray>, magnus::Error> {
+    let args = scan_args::<(RbArrayOfHashes,), (Option<RHash>,), (), (), (), ()>(args)?;
+    let (rb_array_of_hashes,) = args.required;
+    let (rb_options,) = args.optional;
+    let options = VroomOptions::from_rhash(rb_options)?;
+    let client = client_for(rb_options)?;
+
+    rb_send(&client, rb_array_of_hashes, &options)
+}
+
+/// Converts the ruby requests, sends them and converts the responses back for ruby.
+/// With a block each response is yielded as `|index, response|` as soon as it finishes
+/// and nil is returned, otherwise they're all returned in order once the batch is done
+pub fn rb_send(
+    client: &Client,
     rb_array_of_hashes: RbArrayOfHashes,
-) -> Result<magnus::RArray, magnus::Error> {
+    options: &VroomOptions,
+) -> Result<Option<RArray>, magnus::Error> {
     // Take ruby argument, converted to rust types
     // then convert them into vroom requests, which will validate them also
-    let mut vroom_requests: Vec<Request> = Vec::new();
+    let mut vroom_requests: Vec<Request> = Vec::with_capacity(rb_array_of_hashes.len());
+    let (base_url, balanced) = client.base_url_for(&options.batch);
 
     for rb_hash_as_rust_type in rb_array_of_hashes.into_iter() {
-        let request = Request::from_hashmap(rb_hash_as_rust_type)?;
+        let request = request::from_hashmap(rb_hash_as_rust_type, base_url, balanced)?;
         vroom_requests.push(request);
     }
 
-    // send the requests and return vroom responses on a single threaded tokio runtime
-    let rt = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .max_blocking_threads(1)
-        .build()
-        .unwrap();
-    let vroom_responses = rt.block_on(batch_send_api_requests(vroom_requests));
-
-    // convert them from vroom responses types back into rust types convertable to ruby
-    let ruby_array_of_hash_responses: RbArrayOfHashes = vroom_responses
-        .into_iter()
-        .map(|response| response.into_hashmap())
-        .collect();
+    let mut recorder = match &options.cassette {
+        Some(cassette) if cassette.mode == CassetteMode::Record => {
+            Some(cassette.recorder(&vroom_requests)?)
+        }
+        _ => None,
+    };
 
-    Ok(magnus::RArray::from_vec(ruby_array_of_hash_responses))
-}
-
-/// Execute API calls async with reqwest
-async fn batch_send_api_requests(requests: Vec<Request>) -> Vec<Response> {
-    let mut responses: Vec<Response> = Vec::with_capacity(requests.len());
-    let mut set = tokio::task::JoinSet::new();
+    let block_given = block::block_given();
+    let mut ruby_array_of_hash_responses: Vec<RHash> = Vec::new();
+    let mut respond = |response: VroomResponse| -> Result<(), magnus::Error> {
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.record(&response.response)?;
+        }
+        if options.raise_vroom_errors {
+            raise_vroom_error(&response)?;
+        }
 
-    let client: Arc<reqwest::Client> = Arc::new(reqwest::Client::new());
+        // convert them from vroom responses types back into ruby hashes
+        let index = response.response.sort_key;
+        let rb_hash = response.into_rhash()?;
+        if block_given {
+            block::yield_values::<(i32, RHash), Value>((index, rb_hash))?;
+        } else {
+            ruby_array_of_hash_responses.push(rb_hash);
+        }
+        Ok(())
+    };
 
-    for (sort_key, r) in requests.into_iter().enumerate() {
-        let client: Arc<reqwest::Client> = Arc::clone(&client);
-
-        set.spawn(async move {
-            let reqwest_response = client
-                .post(r.url)
-                .header("Content-Type", "application/json")
-                .body(r.body)
-                .send()
-                .await
-                .unwrap();
+    match &options.cassette {
+        // nothing gets sent, every response comes from the cassette
+        Some(cassette) if cassette.mode == CassetteMode::Replay => {
+            let responses = cassette.replay(&vroom_requests)?;
+            for response in parse_solutions(responses, options)? {
+                respond(response)?;
+            }
+        }
+        _ if block_given => {
+            client.stream_send(vroom_requests, &options.batch, |response| {
+                for response in parse_solutions(vec![response], options)? {
+                    respond(response)?;
+                }
+                Ok(())
+            })?;
+        }
+        _ => {
+            let responses = client.batch_send(vroom_requests, &options.batch)?;
+            for response in parse_solutions(responses, options)? {
+                respond(response)?;
+            }
+        }
+    }
 
-            let http_status_code = reqwest_response.status().as_u16();
-            // consumes self so do it after we get the status code
-            let body = reqwest_response.text().await.unwrap_or("".to_string());
+    if block_given {
+        return Ok(None);
+    }
+    Ok(Some(RArray::from_vec(ruby_array_of_hash_responses)))
+}
 
-            Response {
-                sort_key: sort_key as i32,
-                http_status_code,
-                body,
-            }
-        });
+/// Parses the response bodies if the batch asked for solutions.
+/// Done with the GVL released, big solutions take a while
+fn parse_solutions(
+    responses: Vec<Response>,
+    options: &VroomOptions,
+) -> Result<Vec<VroomResponse>, magnus::Error> {
+    let parse = || {
+        responses
+            .into_iter()
+            .map(|response| VroomResponse::parse(response, options))
+            .collect()
+    };
+    if !options.parse_solutions {
+        return Ok(parse());
     }
 
-    // Run the joinset to completion, they return in the order they finish
-    // so need to sort them again after
-    while let Some(res) = set.join_next().await {
-        let api_response = res.unwrap();
-        responses.push(api_response);
+    // nothing to interrupt, parsing finishes soon enough
+    gvl::without_gvl(parse, || {})
+}
+
+/// Raises vroom's error code for the response as the matching BatchApi::Vroom exception
+fn raise_vroom_error(response: &VroomResponse) -> Result<(), magnus::Error> {
+    match response.vroom_error() {
+        Some((error_kind, message)) => {
+            let rb_error = magnus::Error::new(
+                error_kind.exception_class()?,
+                format!(
+                    "vroom {} error for request {}: {}",
+                    error_kind.as_str().trim_start_matches("vroom_"),
+                    response.response.sort_key,
+                    message
+                ),
+            );
+            Err(rb_error)
+        }
+        None => Ok(()),
     }
-    // order results in the same order they came in
-    responses.sort_by_key(|r| r.sort_key);
-    responses
 }
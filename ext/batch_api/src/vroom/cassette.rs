@@ -0,0 +1,245 @@
+// Records vroom batches to a file and replays them back, so specs
+// and whole planning flows can run without a vroom server.
+// Cassettes are ndjson, one request and the response it got per line
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use magnus::RHash;
+use serde::{Deserialize, Serialize};
+
+use crate::batch::cache::body_key;
+use crate::batch::request::Request;
+use crate::batch::response::{ErrorKind, Outcome, Response};
+use crate::options::{fetch, fetch_name};
+
+use super::response::vroom_exception_class;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    // send requests as normal and append them to the cassette
+    Record,
+    // answer every request from the cassette without sending anything
+    Replay,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cassette {
+    pub path: PathBuf,
+    pub mode: CassetteMode,
+}
+
+// A line of the cassette
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    // hash of the canonical request body, what replays are matched on
+    key: String,
+    path: String,
+    request: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    http_status_code: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error_kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error_message: Option<String>,
+    #[serde(default)]
+    attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    endpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<f64>,
+}
+
+impl Cassette {
+    pub fn from_rhash(rb_hash: RHash) -> Result<Self, magnus::Error> {
+        let path = match fetch::<String>(rb_hash, "path")? {
+            Some(path) => PathBuf::from(path),
+            None => {
+                let rb_error =
+                    magnus::Error::new(magnus::exception::arg_error(), "cassette needs a path");
+                return Err(rb_error);
+            }
+        };
+
+        let mode = match fetch_name(rb_hash, "mode")?.as_deref() {
+            Some("record") => CassetteMode::Record,
+            Some("replay") => CassetteMode::Replay,
+            _ => {
+                let rb_error = magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    "cassette mode must be record or replay",
+                );
+                return Err(rb_error);
+            }
+        };
+
+        Ok(Cassette { path, mode })
+    }
+
+    /// Opens the cassette for appending the batch's responses to
+    pub fn recorder(&self, requests: &[Request]) -> Result<Recorder, magnus::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|err| self.io_error(err))?;
+
+        Ok(Recorder {
+            file,
+            requests: requests
+                .iter()
+                .map(|r| (r.path.clone(), r.body.clone()))
+                .collect(),
+        })
+    }
+
+    /// The recorded response for every request, in order.
+    /// Raises a CassetteError if any of them wasn't recorded
+    pub fn replay(&self, requests: &[Request]) -> Result<Vec<Response>, magnus::Error> {
+        let file = File::open(&self.path).map_err(|err| self.io_error(err))?;
+
+        // recorded more than once, the latest recording wins
+        let mut entries: HashMap<String, Entry> = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|err| self.io_error(err))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let entry: Entry = serde_json::from_str(&line).map_err(|err| {
+                magnus::Error::new(
+                    magnus::exception::arg_error(),
+                    format!("invalid cassette {}: {}", self.path.display(), err),
+                )
+            })?;
+            entries.insert(entry.key.clone(), entry);
+        }
+
+        let mut responses: Vec<Response> = Vec::with_capacity(requests.len());
+        for (sort_key, r) in requests.iter().enumerate() {
+            let entry = match entries.get(&body_key(&r.body)) {
+                Some(entry) => entry,
+                None => {
+                    let rb_error = magnus::Error::new(
+                        vroom_exception_class("CassetteError")?,
+                        format!(
+                            "no response recorded in {} for request {} ({})",
+                            self.path.display(),
+                            sort_key,
+                            r.path
+                        ),
+                    );
+                    return Err(rb_error);
+                }
+            };
+
+            responses.push(Response {
+                sort_key: sort_key as i32,
+                outcome: entry.outcome(),
+                attempts: entry.attempts,
+                endpoint: entry.endpoint.clone(),
+                latency: entry
+                    .latency_ms
+                    .map(|latency_ms| Duration::from_secs_f64(latency_ms.max(0.0) / 1000.0)),
+                queue_wait: None,
+                cache_hit: false,
+            });
+        }
+        Ok(responses)
+    }
+
+    fn io_error(&self, err: std::io::Error) -> magnus::Error {
+        magnus::Error::new(
+            magnus::exception::io_error(),
+            format!("can't open cassette {}: {}", self.path.display(), err),
+        )
+    }
+}
+
+impl Entry {
+    fn outcome(&self) -> Outcome {
+        match (self.http_status_code, &self.error_kind) {
+            (Some(http_status_code), _) => Outcome::Http {
+                http_status_code,
+                body: self.body.clone().unwrap_or_default(),
+                headers: self.headers.clone(),
+            },
+            (None, error_kind) => Outcome::Error {
+                error_kind: error_kind
+                    .as_deref()
+                    .and_then(ErrorKind::from_name)
+                    .unwrap_or(ErrorKind::Connect),
+                message: self.error_message.clone().unwrap_or_default(),
+            },
+        }
+    }
+}
+
+/// Appends each response of a batch to the cassette as it comes in
+pub struct Recorder {
+    file: File,
+    // path and body of each request, by index
+    requests: Vec<(String, String)>,
+}
+
+impl Recorder {
+    pub fn record(&mut self, response: &Response) -> Result<(), magnus::Error> {
+        let (path, request) = match self.requests.get(response.sort_key as usize) {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+
+        let mut entry = Entry {
+            key: body_key(request),
+            path: path.clone(),
+            request: request.clone(),
+            http_status_code: None,
+            body: None,
+            headers: Vec::new(),
+            error_kind: None,
+            error_message: None,
+            attempts: response.attempts,
+            endpoint: response.endpoint.clone(),
+            latency_ms: response
+                .latency
+                .map(|latency| latency.as_secs_f64() * 1000.0),
+        };
+        match &response.outcome {
+            Outcome::Http {
+                http_status_code,
+                body,
+                headers,
+            } => {
+                entry.http_status_code = Some(*http_status_code);
+                entry.body = Some(body.clone());
+                entry.headers = headers.clone();
+            }
+            Outcome::Error {
+                error_kind,
+                message,
+            } => {
+                entry.error_kind = Some(error_kind.as_str().to_string());
+                entry.error_message = Some(message.clone());
+            }
+        }
+
+        // written in one go so lines from batches recording at the same time don't interleave
+        let mut line = serde_json::to_string(&entry).map_err(|err| {
+            magnus::Error::new(magnus::exception::runtime_error(), err.to_string())
+        })?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes()).map_err(|err| {
+            magnus::Error::new(
+                magnus::exception::io_error(),
+                format!("can't record to cassette: {}", err),
+            )
+        })
+    }
+}
@@ -0,0 +1,55 @@
+use magnus::{scan_args::scan_args, RArray, RHash, Value};
+
+use crate::batch::client::{Client as BatchClient, RbArrayOfHashes};
+use crate::batch::options::ClientOptions;
+
+use super::api::rb_send;
+use super::options::VroomOptions;
+
+/// A long lived vroom client, keeping its runtime and connections between batches.
+/// Safe to share between ruby threads, but create it after forking
+#[magnus::wrap(class = "BatchApi::Vroom::Client", free_immediately)]
+pub struct Client {
+    client: BatchClient,
+}
+
+impl Client {
+    // Functions for our ruby interface
+
+    /// `Client.new(base_url: ..., endpoints: [...], balance: :round_robin, eject_after: ..., eject_for: ...,
+    /// pool_size: ..., connect_timeout: ..., request_timeout: ..., cache: { max_entries: ..., ttl: ... },
+    /// store: { path: ..., max_age: ..., max_bytes: ... }, headers: { ... }, auth: { bearer: ... },
+    /// tls: { ca_file: ..., cert_file: ..., key_file: ..., min_version: ..., insecure: ... }, proxy: { url: ..., no_proxy: [...] },
+    /// rate_limit: { requests_per_second: ..., burst: ..., endpoints: { url => { ... } } },
+    /// circuit_breaker: { failure_threshold: ..., reset_timeout: ..., half_open_requests: ... })`
+    pub fn rb_new(args: &[Value]) -> Result<Self, magnus::Error> {
+        let args = scan_args::<(), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (rb_options,) = args.optional;
+
+        Ok(Client {
+            client: BatchClient::new(ClientOptions::from_rhash(rb_options)?)?,
+        })
+    }
+
+    /// `client.auth = { bearer: ... }`, see BatchClient#rb_set_auth
+    pub fn rb_set_auth(&self, rb_auth: Option<RHash>) -> Result<(), magnus::Error> {
+        self.client.rb_set_auth(rb_auth)
+    }
+
+    /// `client.circuits`, see BatchClient#rb_circuits
+    pub fn rb_circuits(&self) -> Result<RHash, magnus::Error> {
+        self.client.rb_circuits()
+    }
+
+    /// `client.batch_send(requests, base_url: ..., max_concurrency: ..., batch_timeout: ..., retry: { ... },
+    /// cancellation_token: ...)`,
+    /// optionally with a block to stream responses as they finish
+    pub fn rb_batch_send(&self, args: &[Value]) -> Result<Option<RArray>, magnus::Error> {
+        let args = scan_args::<(RbArrayOfHashes,), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (rb_array_of_hashes,) = args.required;
+        let (rb_options,) = args.optional;
+        let options = VroomOptions::from_rhash(rb_options)?;
+
+        rb_send(&self.client, rb_array_of_hashes, &options)
+    }
+}
@@ -0,0 +1,376 @@
+// A stand-in vroom for testing how batches behave, answers requests on a local port
+// with canned solutions, error codes, delays or dropped connections.
+// Only speaks as much http/1.1 as reqwest needs: content-length bodies and keep-alive
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use magnus::{scan_args::scan_args, RHash, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+use crate::batch::compression::Encoding;
+use crate::options::{fetch, fetch_duration, fetch_name};
+
+// an empty but valid vroom solution
+const EMPTY_SOLUTION: &str = r#"{"code":0,"summary":{"cost":0,"routes":0,"unassigned":0,"setup":0,"service":0,"duration":0,"waiting_time":0,"priority":0,"violations":[],"computing_times":{"loading":0,"solving":0,"routing":0}},"unassigned":[],"routes":[]}"#;
+
+/// How the mock answers a request
+#[derive(Debug, Clone)]
+struct MockResponse {
+    status: u16,
+    body: String,
+    headers: Vec<(String, String)>,
+    // waits this long before answering
+    delay: Option<Duration>,
+    // closes the connection without answering at all
+    drop: bool,
+    // compresses the body and sends the Content-Encoding for it
+    encoding: Option<Encoding>,
+}
+
+impl Default for MockResponse {
+    fn default() -> Self {
+        MockResponse {
+            status: 200,
+            body: EMPTY_SOLUTION.to_string(),
+            headers: Vec::new(),
+            delay: None,
+            drop: false,
+            encoding: None,
+        }
+    }
+}
+
+impl MockResponse {
+    /// `{ body: '...', status: 200, code: 2, error: '...', headers: { ... }, delay: 0.5, drop: true, encoding: :gzip }`
+    /// A vroom error code without a body makes one up, with the status vroom-express would use
+    fn from_rhash(rb_hash: RHash) -> Result<Self, magnus::Error> {
+        let mut response = Self::default();
+
+        if let Some(code) = fetch::<u32>(rb_hash, "code")? {
+            let error = fetch::<String>(rb_hash, "error")?
+                .unwrap_or_else(|| String::from("mock vroom error"));
+            response.body = serde_json::json!({ "code": code, "error": error }).to_string();
+            response.status = match code {
+                0 => 200,
+                2 => 400,
+                _ => 500,
+            };
+        }
+        if let Some(body) = fetch::<String>(rb_hash, "body")? {
+            response.body = body;
+        }
+        if let Some(status) = fetch::<u16>(rb_hash, "status")? {
+            response.status = status;
+        }
+        if let Some(headers) = fetch::<RHash>(rb_hash, "headers")? {
+            response.headers = headers
+                .to_hash_map::<String, String>()?
+                .into_iter()
+                .collect();
+        }
+        response.delay = fetch_duration(rb_hash, "delay")?;
+        response.drop = fetch::<bool>(rb_hash, "drop")?.unwrap_or(false);
+
+        if let Some(name) = fetch_name(rb_hash, "encoding")? {
+            response.encoding = match Encoding::from_name(&name) {
+                Some(encoding) => Some(encoding),
+                None => {
+                    let rb_error = magnus::Error::new(
+                        magnus::exception::arg_error(),
+                        "mock response encoding must be gzip or br",
+                    );
+                    return Err(rb_error);
+                }
+            };
+        }
+
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // answers the next requests in order, once each
+    queue: VecDeque<MockResponse>,
+    // answers every request whose body or request line contains the string
+    rules: Vec<(String, MockResponse)>,
+    default: MockResponse,
+    // bodies of every request received, in order
+    requests: Vec<String>,
+    // and their methods and paths, e.g. `POST /`
+    request_lines: Vec<String>,
+    // and their headers, names lowercased
+    request_headers: Vec<HashMap<String, String>>,
+}
+
+impl State {
+    fn respond_to(
+        &mut self,
+        request_line: String,
+        body: String,
+        headers: HashMap<String, String>,
+    ) -> MockResponse {
+        let response = match self
+            .rules
+            .iter()
+            .find(|(matching, _)| body.contains(matching) || request_line.contains(matching))
+        {
+            Some((_, response)) => response.clone(),
+            None => self
+                .queue
+                .pop_front()
+                .unwrap_or_else(|| self.default.clone()),
+        };
+        self.requests.push(body);
+        self.request_lines.push(request_line);
+        self.request_headers.push(headers);
+        response
+    }
+}
+
+#[magnus::wrap(class = "BatchApi::Vroom::MockServer", free_immediately)]
+pub struct MockServer {
+    // dropping it when ruby frees the server stops anything still running
+    _runtime: tokio::runtime::Runtime,
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    // true once stopped, kept so connections accepted just before still see it
+    shutdown: watch::Sender<bool>,
+}
+
+impl MockServer {
+    // Functions for our ruby interface
+
+    /// `MockServer.start(port: 0, default: { ... })`, picks a free port unless given one
+    pub fn rb_start(args: &[Value]) -> Result<Self, magnus::Error> {
+        let args = scan_args::<(), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (rb_options,) = args.optional;
+
+        let mut state = State::default();
+        let mut port: u16 = 0;
+        if let Some(rb_options) = rb_options {
+            port = fetch::<u16>(rb_options, "port")?.unwrap_or(0);
+            if let Some(rb_default) = fetch::<RHash>(rb_options, "default")? {
+                state.default = MockResponse::from_rhash(rb_default)?;
+            }
+        }
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .map_err(|err| {
+                magnus::Error::new(magnus::exception::runtime_error(), err.to_string())
+            })?;
+
+        // bound here rather than on the runtime so the port is known before start returns
+        let listener = std::net::TcpListener::bind(("127.0.0.1", port))
+            .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+            .map_err(|err| magnus::Error::new(magnus::exception::io_error(), err.to_string()))?;
+        let address = listener
+            .local_addr()
+            .map_err(|err| magnus::Error::new(magnus::exception::io_error(), err.to_string()))?;
+        let listener = {
+            let _runtime = runtime.enter();
+            TcpListener::from_std(listener)
+                .map_err(|err| magnus::Error::new(magnus::exception::io_error(), err.to_string()))?
+        };
+
+        let state = Arc::new(Mutex::new(state));
+        let (shutdown, stopped) = watch::channel(false);
+        runtime.spawn(serve(listener, Arc::clone(&state), stopped));
+
+        Ok(MockServer {
+            _runtime: runtime,
+            address,
+            state,
+            shutdown,
+        })
+    }
+
+    pub fn rb_url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    pub fn rb_port(&self) -> u16 {
+        self.address.port()
+    }
+
+    /// Answers the next request that doesn't match a rule with this response
+    pub fn rb_enqueue(&self, rb_response: RHash) -> Result<(), magnus::Error> {
+        let response = MockResponse::from_rhash(rb_response)?;
+        self.state().queue.push_back(response);
+        Ok(())
+    }
+
+    /// Answers every request whose body, or method and path, contains `matching` with this response
+    pub fn rb_on(&self, matching: String, rb_response: RHash) -> Result<(), magnus::Error> {
+        let response = MockResponse::from_rhash(rb_response)?;
+        self.state().rules.push((matching, response));
+        Ok(())
+    }
+
+    /// Bodies of every request received so far
+    pub fn rb_requests(&self) -> Vec<String> {
+        self.state().requests.clone()
+    }
+
+    /// Method and path of every request received so far, e.g. `GET /search?q=York`
+    pub fn rb_request_lines(&self) -> Vec<String> {
+        self.state().request_lines.clone()
+    }
+
+    /// Headers of every request received so far, names lowercased
+    pub fn rb_request_headers(&self) -> Vec<HashMap<String, String>> {
+        self.state().request_headers.clone()
+    }
+
+    /// Stops accepting connections and drops the open ones, the server can't be started again
+    pub fn rb_stop(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+async fn serve(listener: TcpListener, state: Arc<Mutex<State>>, stopped: watch::Receiver<bool>) {
+    loop {
+        let mut accept_stopped = stopped.clone();
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(_) => continue,
+            },
+            _ = accept_stopped.wait_for(|stopped| *stopped) => return,
+        };
+
+        let state = Arc::clone(&state);
+        let mut connection_stopped = stopped.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = serve_connection(stream, state) => {}
+                _ = connection_stopped.wait_for(|stopped| *stopped) => {}
+            }
+        });
+    }
+}
+
+// Answers requests on a keep-alive connection until the client closes it
+async fn serve_connection(stream: TcpStream, state: Arc<Mutex<State>>) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+
+    loop {
+        let (request_line, headers, body) = match read_request(&mut stream).await? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+
+        let response = state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .respond_to(request_line, body, headers);
+
+        if let Some(delay) = response.delay {
+            tokio::time::sleep(delay).await;
+        }
+        if response.drop {
+            // dropping the stream closes the connection with nothing sent
+            return Ok(());
+        }
+
+        let mut body = response.body.into_bytes();
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\n",
+            response.status,
+            reason_phrase(response.status)
+        );
+        if let Some(encoding) = response.encoding {
+            body = encoding.compress(&body, encoding.default_level())?;
+            head.push_str(&format!("Content-Encoding: {}\r\n", encoding.as_str()));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        for (name, value) in response.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+
+        let stream = stream.get_mut();
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&body).await?;
+        stream.flush().await?;
+    }
+}
+
+// The method and path, headers and body of the next request on the connection,
+// the body decompressed if it came compressed. None once the client has closed it
+async fn read_request(
+    stream: &mut BufReader<TcpStream>,
+) -> std::io::Result<Option<(String, HashMap<String, String>, String)>> {
+    let mut line = String::new();
+
+    // request line, answered whatever the method and path
+    if stream.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    // without the http version
+    let request_line = line
+        .trim_end()
+        .rsplit_once(' ')
+        .map(|(request_line, _)| request_line.to_string())
+        .unwrap_or_default();
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let content_length: usize = headers
+        .get("content-length")
+        .and_then(|content_length| content_length.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await?;
+
+    let encoding = headers
+        .get("content-encoding")
+        .and_then(|encoding| Encoding::from_name(encoding));
+    if let Some(encoding) = encoding {
+        body = encoding.decompress(&body)?;
+    }
+    Ok(Some((
+        request_line,
+        headers,
+        String::from_utf8_lossy(&body).into_owned(),
+    )))
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
@@ -1,4 +1,10 @@
+mod cassette;
+mod options;
 mod request;
-mod response;
+pub(crate) mod response;
+pub(crate) mod solution;
 
 pub mod api;
+pub mod client;
+pub mod mock_server;
+pub mod problem;
@@ -0,0 +1,43 @@
+use magnus::RHash;
+
+use crate::batch::options::BatchOptions;
+use crate::options::fetch;
+
+use super::cassette::Cassette;
+
+/// Options for a batch of vroom requests, vroom's own on top of the ones every batch takes
+#[derive(Debug, Default, Clone)]
+pub struct VroomOptions {
+    pub batch: BatchOptions,
+    // parse response bodies into solutions, sorting out vroom's error codes
+    pub parse_solutions: bool,
+    // raise the first vroom error code in the batch as an exception, implies parse_solutions
+    pub raise_vroom_errors: bool,
+    // nested `cassette: { path: ..., mode: :record }` hash to record or replay the batch
+    pub cassette: Option<Cassette>,
+}
+
+impl VroomOptions {
+    pub fn from_rhash(rb_hash: Option<RHash>) -> Result<Self, magnus::Error> {
+        let mut options = VroomOptions {
+            batch: BatchOptions::from_rhash(rb_hash)?,
+            ..Self::default()
+        };
+
+        // no options hash passed, keep the defaults
+        let rb_hash = match rb_hash {
+            Some(rb_hash) => rb_hash,
+            None => return Ok(options),
+        };
+
+        if let Some(rb_cassette_hash) = fetch::<RHash>(rb_hash, "cassette")? {
+            options.cassette = Some(Cassette::from_rhash(rb_cassette_hash)?);
+        }
+
+        options.raise_vroom_errors = fetch::<bool>(rb_hash, "raise_vroom_errors")?.unwrap_or(false);
+        options.parse_solutions = options.raise_vroom_errors
+            || fetch::<bool>(rb_hash, "parse_solutions")?.unwrap_or(false);
+
+        Ok(options)
+    }
+}
@@ -0,0 +1,351 @@
+use std::cell::RefCell;
+
+use magnus::RHash;
+
+use super::{
+    Break, Job, Matrices, Problem, ProblemOptions, Shipment, ShipmentStep, TimeWindow, Vehicle,
+    VehicleCosts,
+};
+use crate::options::Fields;
+
+// same newtype + refcell pattern as the zipcode MemStore,
+// magnus won't give us &mut self in methods exposed to ruby
+#[magnus::wrap(class = "BatchApi::Vroom::Problem", free_immediately)]
+pub struct MutProblem(RefCell<Problem>);
+
+impl MutProblem {
+    pub fn rb_new() -> MutProblem {
+        MutProblem(RefCell::new(Problem::default()))
+    }
+
+    /// The problem built so far, for helpers that rewrite it like BatchApi::Osrm::Client#add_matrices
+    pub(crate) fn problem(&self) -> &RefCell<Problem> {
+        &self.0
+    }
+
+    // Functions for our ruby interface
+
+    pub fn rb_add_vehicle(&self, rb_hash: RHash) -> Result<(), magnus::Error> {
+        let vehicle = vehicle_from_rhash(rb_hash)?;
+        let mut problem = self.0.borrow_mut();
+
+        if problem.vehicles.iter().any(|v| v.id == vehicle.id) {
+            return Err(duplicate_id_error("vehicle", vehicle.id));
+        }
+        problem.vehicles.push(vehicle);
+        Ok(())
+    }
+
+    pub fn rb_add_job(&self, rb_hash: RHash) -> Result<(), magnus::Error> {
+        let job = job_from_rhash(rb_hash)?;
+        let mut problem = self.0.borrow_mut();
+
+        if problem.jobs.iter().any(|j| j.id == job.id) {
+            return Err(duplicate_id_error("job", job.id));
+        }
+        problem.jobs.push(job);
+        Ok(())
+    }
+
+    pub fn rb_add_shipment(&self, rb_hash: RHash) -> Result<(), magnus::Error> {
+        let shipment = shipment_from_rhash(rb_hash)?;
+        let mut problem = self.0.borrow_mut();
+
+        // pickup and delivery ids share a namespace across every shipment
+        let taken = |id: u64| {
+            problem
+                .shipments
+                .iter()
+                .any(|s| s.pickup.id == id || s.delivery.id == id)
+        };
+        if taken(shipment.pickup.id) {
+            return Err(duplicate_id_error("shipment pickup", shipment.pickup.id));
+        }
+        if taken(shipment.delivery.id) {
+            return Err(duplicate_id_error(
+                "shipment delivery",
+                shipment.delivery.id,
+            ));
+        }
+        problem.shipments.push(shipment);
+        Ok(())
+    }
+
+    /// `add_matrices('car', durations: [[...]], distances: [[...]], costs: [[...]])`
+    pub fn rb_add_matrices(&self, profile: String, rb_hash: RHash) -> Result<(), magnus::Error> {
+        let fields = Fields::new(rb_hash, "matrices", &["durations", "distances", "costs"])?;
+        let matrices = Matrices {
+            durations: fields.matrix("durations")?,
+            distances: fields.matrix("distances")?,
+            costs: fields.matrix("costs")?,
+        };
+
+        self.0.borrow_mut().matrices.insert(profile, matrices);
+        Ok(())
+    }
+
+    /// Asks vroom to include route geometry in the solution
+    pub fn rb_set_geometry(&self, geometry: bool) {
+        self.0.borrow_mut().options = Some(ProblemOptions { g: Some(geometry) });
+    }
+
+    /// The json request body, also used for to_str so a problem can be given as a batch body
+    pub fn rb_to_json(&self) -> Result<String, magnus::Error> {
+        let problem = self.0.borrow();
+
+        problem
+            .validate()
+            .and_then(|_| problem.to_json())
+            .map_err(|err| magnus::Error::new(magnus::exception::arg_error(), err))
+    }
+
+    /// The json without validating, so printing a problem that's still being built doesn't raise
+    pub fn rb_to_s(&self) -> Result<String, magnus::Error> {
+        self.0
+            .borrow()
+            .to_json()
+            .map_err(|err| magnus::Error::new(magnus::exception::runtime_error(), err))
+    }
+
+    /// `#<BatchApi::Vroom::Problem vehicles=1 jobs=0 shipments=0>`
+    pub fn rb_inspect(&self) -> String {
+        let problem = self.0.borrow();
+        format!(
+            "#<BatchApi::Vroom::Problem vehicles={} jobs={} shipments={}>",
+            problem.vehicles.len(),
+            problem.jobs.len(),
+            problem.shipments.len()
+        )
+    }
+}
+
+fn vehicle_from_rhash(rb_hash: RHash) -> Result<Vehicle, magnus::Error> {
+    let fields = Fields::new(
+        rb_hash,
+        "vehicle",
+        &[
+            "id",
+            "profile",
+            "description",
+            "start",
+            "start_index",
+            "end",
+            "end_index",
+            "capacity",
+            "costs",
+            "skills",
+            "time_window",
+            "breaks",
+            "speed_factor",
+            "max_tasks",
+            "max_travel_time",
+            "max_distance",
+        ],
+    )?;
+
+    let costs = match fields.get::<RHash>("costs")? {
+        Some(rb_costs) => {
+            let costs = Fields::new(rb_costs, "vehicle costs", &["fixed", "per_hour", "per_km"])?;
+            Some(VehicleCosts {
+                fixed: costs.get("fixed")?,
+                per_hour: costs.get("per_hour")?,
+                per_km: costs.get("per_km")?,
+            })
+        }
+        None => None,
+    };
+
+    let breaks = fields
+        .list::<RHash>("breaks")?
+        .into_iter()
+        .map(break_from_rhash)
+        .collect::<Result<Vec<Break>, magnus::Error>>()?;
+
+    let vehicle = Vehicle {
+        id: fields.required("id")?,
+        profile: fields.get("profile")?,
+        description: fields.get("description")?,
+        start: fields.location("start")?,
+        start_index: fields.get("start_index")?,
+        end: fields.location("end")?,
+        end_index: fields.get("end_index")?,
+        capacity: fields.list("capacity")?,
+        costs,
+        skills: fields.list("skills")?,
+        time_window: fields.time_window("time_window")?,
+        breaks,
+        speed_factor: fields.get("speed_factor")?,
+        max_tasks: fields.get("max_tasks")?,
+        max_travel_time: fields.get("max_travel_time")?,
+        max_distance: fields.get("max_distance")?,
+    };
+
+    // vroom needs somewhere for the route to start or end
+    let has_start = vehicle.start.is_some() || vehicle.start_index.is_some();
+    let has_end = vehicle.end.is_some() || vehicle.end_index.is_some();
+    if !has_start && !has_end {
+        return Err(fields.error("needs a start or an end"));
+    }
+
+    Ok(vehicle)
+}
+
+fn break_from_rhash(rb_hash: RHash) -> Result<Break, magnus::Error> {
+    let fields = Fields::new(
+        rb_hash,
+        "break",
+        &["id", "time_windows", "service", "description", "max_load"],
+    )?;
+
+    Ok(Break {
+        id: fields.required("id")?,
+        time_windows: fields.time_windows("time_windows")?,
+        service: fields.get("service")?,
+        description: fields.get("description")?,
+        max_load: fields.list("max_load")?,
+    })
+}
+
+fn job_from_rhash(rb_hash: RHash) -> Result<Job, magnus::Error> {
+    let fields = Fields::new(
+        rb_hash,
+        "job",
+        &[
+            "id",
+            "description",
+            "location",
+            "location_index",
+            "setup",
+            "service",
+            "delivery",
+            "pickup",
+            "skills",
+            "priority",
+            "time_windows",
+        ],
+    )?;
+
+    let job = Job {
+        id: fields.required("id")?,
+        description: fields.get("description")?,
+        location: fields.location("location")?,
+        location_index: fields.get("location_index")?,
+        setup: fields.get("setup")?,
+        service: fields.get("service")?,
+        delivery: fields.list("delivery")?,
+        pickup: fields.list("pickup")?,
+        skills: fields.list("skills")?,
+        priority: fields.priority()?,
+        time_windows: fields.time_windows("time_windows")?,
+    };
+
+    if job.location.is_none() && job.location_index.is_none() {
+        return Err(fields.error("needs a location or a location_index"));
+    }
+
+    Ok(job)
+}
+
+fn shipment_from_rhash(rb_hash: RHash) -> Result<Shipment, magnus::Error> {
+    let fields = Fields::new(
+        rb_hash,
+        "shipment",
+        &["pickup", "delivery", "amount", "skills", "priority"],
+    )?;
+
+    Ok(Shipment {
+        pickup: shipment_step_from_rhash(fields.required("pickup")?, "shipment pickup")?,
+        delivery: shipment_step_from_rhash(fields.required("delivery")?, "shipment delivery")?,
+        amount: fields.list("amount")?,
+        skills: fields.list("skills")?,
+        priority: fields.priority()?,
+    })
+}
+
+fn shipment_step_from_rhash(
+    rb_hash: RHash,
+    what: &'static str,
+) -> Result<ShipmentStep, magnus::Error> {
+    let fields = Fields::new(
+        rb_hash,
+        what,
+        &[
+            "id",
+            "description",
+            "location",
+            "location_index",
+            "setup",
+            "service",
+            "time_windows",
+        ],
+    )?;
+
+    let step = ShipmentStep {
+        id: fields.required("id")?,
+        description: fields.get("description")?,
+        location: fields.location("location")?,
+        location_index: fields.get("location_index")?,
+        setup: fields.get("setup")?,
+        service: fields.get("service")?,
+        time_windows: fields.time_windows("time_windows")?,
+    };
+
+    if step.location.is_none() && step.location_index.is_none() {
+        return Err(fields.error("needs a location or a location_index"));
+    }
+
+    Ok(step)
+}
+
+fn duplicate_id_error(what: &str, id: u64) -> magnus::Error {
+    magnus::Error::new(
+        magnus::exception::arg_error(),
+        format!("{} id {} has already been added", what, id),
+    )
+}
+
+// The problem's own field types, on top of the common ones
+impl Fields {
+    fn time_window(&self, key: &str) -> Result<Option<TimeWindow>, magnus::Error> {
+        match self.get::<Vec<u32>>(key)? {
+            Some(time_window) => Ok(Some(self.check_time_window(key, time_window)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn time_windows(&self, key: &str) -> Result<Vec<TimeWindow>, magnus::Error> {
+        self.list::<Vec<u32>>(key)?
+            .into_iter()
+            .map(|time_window| self.check_time_window(key, time_window))
+            .collect()
+    }
+
+    fn check_time_window(
+        &self,
+        key: &str,
+        time_window: Vec<u32>,
+    ) -> Result<TimeWindow, magnus::Error> {
+        match time_window[..] {
+            [start, end] if start <= end => Ok([start, end]),
+            _ => Err(self.error(&format!("{} should be [start, end] with start <= end", key))),
+        }
+    }
+
+    fn matrix(&self, key: &str) -> Result<Option<Vec<Vec<u32>>>, magnus::Error> {
+        match self.get::<Vec<Vec<u32>>>(key)? {
+            Some(matrix) if matrix.iter().all(|row| row.len() == matrix.len()) => Ok(Some(matrix)),
+            Some(_) => Err(self.error(&format!("{} should be a square matrix", key))),
+            None => Ok(None),
+        }
+    }
+
+    // vroom only takes priorities from 0 to 100
+    fn priority(&self) -> Result<Option<u32>, magnus::Error> {
+        match self.get::<u32>("priority")? {
+            Some(priority) if priority > 100 => {
+                Err(self.error("priority should be between 0 and 100"))
+            }
+            priority => Ok(priority),
+        }
+    }
+}
@@ -0,0 +1,226 @@
+// Rust types for vroom's input model, see
+// https://github.com/VROOM-Project/vroom/blob/master/docs/API.md#input
+// Optional fields are left out of the json entirely when not set so vroom applies its defaults
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+pub use crate::options::Location;
+
+pub mod builder;
+
+// [start, end] in seconds
+pub type TimeWindow = [u32; 2];
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Problem {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vehicles: Vec<Vehicle>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub jobs: Vec<Job>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shipments: Vec<Shipment>,
+    // keyed by vehicle routing profile, e.g. "car"
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub matrices: BTreeMap<String, Matrices>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<ProblemOptions>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Vehicle {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<Location>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_index: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<Location>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_index: Option<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capacity: Vec<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub costs: Option<VehicleCosts>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skills: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_window: Option<TimeWindow>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub breaks: Vec<Break>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed_factor: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tasks: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_travel_time: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_distance: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct VehicleCosts {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fixed: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_hour: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_km: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Break {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub time_windows: Vec<TimeWindow>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub max_load: Vec<i64>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location_index: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub setup: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub delivery: Vec<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pickup: Vec<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skills: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub time_windows: Vec<TimeWindow>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Shipment {
+    pub pickup: ShipmentStep,
+    pub delivery: ShipmentStep,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub amount: Vec<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skills: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ShipmentStep {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location_index: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub setup: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub time_windows: Vec<TimeWindow>,
+}
+
+// square matrices, rows and columns follow the location indices
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Matrices {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub durations: Option<Vec<Vec<u32>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distances: Option<Vec<Vec<u32>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub costs: Option<Vec<Vec<u32>>>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ProblemOptions {
+    // ask vroom to return route geometry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub g: Option<bool>,
+}
+
+impl Problem {
+    /// Catches the mistakes vroom would answer with an input error (code 2)
+    /// that can't be caught one field at a time
+    pub fn validate(&self) -> Result<(), String> {
+        if self.vehicles.is_empty() {
+            return Err("a vroom problem needs at least one vehicle".to_string());
+        }
+
+        if self.jobs.is_empty() && self.shipments.is_empty() {
+            return Err("a vroom problem needs at least one job or shipment".to_string());
+        }
+
+        // every capacity and amount has to have the same number of dimensions
+        let mut amount_sizes = self
+            .vehicles
+            .iter()
+            .map(|vehicle| ("vehicle", vehicle.id, vehicle.capacity.len()))
+            .chain(self.jobs.iter().flat_map(|job| {
+                [
+                    ("job", job.id, job.delivery.len()),
+                    ("job", job.id, job.pickup.len()),
+                ]
+            }))
+            .chain(
+                self.shipments
+                    .iter()
+                    .map(|shipment| ("shipment", shipment.pickup.id, shipment.amount.len())),
+            )
+            .filter(|(_, _, size)| *size > 0);
+
+        if let Some((_, _, expected_size)) = amount_sizes.next() {
+            for (kind, id, size) in amount_sizes {
+                if size != expected_size {
+                    return Err(format!(
+                        "{} {} has amounts with {} dimensions, expected {}",
+                        kind, id, size, expected_size
+                    ));
+                }
+            }
+        }
+
+        // locations either all come as coordinates or all as matrix indices
+        if !self.matrices.is_empty() {
+            let uses_coordinates = self.vehicles.iter().any(|vehicle| {
+                (vehicle.start.is_some() && vehicle.start_index.is_none())
+                    || (vehicle.end.is_some() && vehicle.end_index.is_none())
+            }) || self.jobs.iter().any(|job| job.location_index.is_none())
+                || self.shipments.iter().any(|shipment| {
+                    shipment.pickup.location_index.is_none()
+                        || shipment.delivery.location_index.is_none()
+                });
+
+            if uses_coordinates {
+                return Err(
+                    "problems with matrices need a location_index for every location".to_string(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string(self).map_err(|err| err.to_string())
+    }
+}
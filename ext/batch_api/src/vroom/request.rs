@@ -1,40 +1,102 @@
+// Requests for vroom, always posted with a json problem as the body
+
 use std::collections::HashMap;
 
-/// Represents an API request to be sent to vroom
-#[derive(Debug)]
-pub struct Request {
-    pub url: String,
-    pub body: String,
+use magnus::{RHash, TryConvert, Value};
+use reqwest::header::HeaderMap;
+use reqwest::Method;
+
+use crate::batch::auth::header_map;
+use crate::batch::request::{join_url, Request};
+
+/// The url key picks the endpoint, either a full url or a path joined onto the base url.
+/// The base url comes from the batch options, then the client's balanced endpoints,
+/// then the client's base url, falling back to the VROOM_URL env var.
+/// An optional headers key holds a hash of headers for this request alone
+pub fn from_hashmap(
+    hashmap: HashMap<String, Value>,
+    base_url: Option<&str>,
+    balanced: bool,
+) -> Result<Request, magnus::Error> {
+    // Check presence of body key value pair in the hash
+    // required to build the vroom request
+    let body = match hashmap.get("body") {
+        Some(json_string) => String::try_convert(*json_string)?,
+        None => {
+            let rb_error = magnus::Error::new(
+                magnus::exception::arg_error(),
+                "expected hashes with url and body key pairs",
+            );
+            return Err(rb_error);
+        }
+    };
+
+    let path = match hashmap.get("url") {
+        Some(url) => String::try_convert(*url)?,
+        None => String::new(),
+    };
+
+    let headers = match hashmap.get("headers") {
+        Some(rb_headers) => header_map(RHash::try_convert(*rb_headers)?)?,
+        None => HeaderMap::new(),
+    };
+
+    // full urls go to that vroom instance as they are
+    if let Ok(url) = reqwest::Url::parse(&path) {
+        let mut path = url.path().to_string();
+        if let Some(query) = url.query() {
+            path = format!("{}?{}", path, query);
+        }
+
+        return Ok(Request {
+            method: Method::POST,
+            base_url: Some(url.origin().ascii_serialization()),
+            path,
+            query: Vec::new(),
+            body,
+            headers,
+        });
+    }
+
+    let base_url = match base_url {
+        Some(base_url) => Some(base_url.to_string()),
+        None if balanced => None,
+        None => Some(vroom_url_env_var()?),
+    };
+
+    // catch bad urls here rather than as connect errors later on
+    if let Some(base_url) = base_url.as_deref() {
+        let url = join_url(base_url, &path);
+        if let Err(err) = reqwest::Url::parse(&url) {
+            let rb_error = magnus::Error::new(
+                magnus::exception::arg_error(),
+                format!("invalid vroom url {}: {}", url, err),
+            );
+            return Err(rb_error);
+        }
+    }
+
+    Ok(Request {
+        method: Method::POST,
+        base_url,
+        path,
+        query: Vec::new(),
+        body,
+        headers,
+    })
 }
 
-impl Request {
-    pub fn from_hashmap(hashmap: HashMap<String, String>) -> Result<Self, magnus::Error> {
-        // Check presence of body key value pair in the hash
-        // required to build the vroom request
-        let body = match hashmap.get("body") {
-            Some(json_string) => json_string.to_string(),
-            None => {
-                let rb_error = magnus::Error::new(
-                    magnus::exception::arg_error(),
-                    "expected hashes with url and body key pairs",
-                );
-                return Err(rb_error);
-            }
-        };
-
-        // Check presence of the vroom url environment variable
-        // required to build the vroom request
-        let url = match std::env::var("VROOM_URL") {
-            Ok(vroom_url) => vroom_url,
-            Err(_) => {
-                let rb_error = magnus::Error::new(
-                    magnus::exception::arg_error(),
-                    "missing environment variable VROOM_URL",
-                );
-                return Err(rb_error);
-            }
-        };
-
-        Ok(Self { url, body })
+// Check presence of the vroom url environment variable
+// required to build the vroom request without a base url
+fn vroom_url_env_var() -> Result<String, magnus::Error> {
+    match std::env::var("VROOM_URL") {
+        Ok(vroom_url) => Ok(vroom_url),
+        Err(_) => {
+            let rb_error = magnus::Error::new(
+                magnus::exception::arg_error(),
+                "missing environment variable VROOM_URL",
+            );
+            Err(rb_error)
+        }
     }
 }
@@ -1,25 +1,104 @@
-use std::collections::HashMap;
+// What vroom answered on top of the batch's response: the parsed solution and vroom's error codes
 
-// Sort id is to optionally sort responses
-// in the same order they were sent
+use magnus::{class, prelude::*, ExceptionClass, RHash, RModule};
 
-#[derive(Debug)]
-pub struct Response {
-    pub sort_key: i32,
-    pub body: String,
-    pub http_status_code: u16,
+use crate::batch::response::{Outcome, Response};
+
+use super::options::VroomOptions;
+use super::solution::Solution;
+
+#[derive(Debug, Clone)]
+pub struct VroomResponse {
+    pub response: Response,
+    // the parsed body, when the batch asks for solutions
+    pub solution: Option<Result<Solution, String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VroomErrorKind {
+    // vroom answered with an error code
+    Internal,
+    Input,
+    Routing,
+    // a successful status whose body isn't vroom output
+    InvalidSolution,
+}
+
+impl VroomErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VroomErrorKind::Internal => "vroom_internal",
+            VroomErrorKind::Input => "vroom_input",
+            VroomErrorKind::Routing => "vroom_routing",
+            VroomErrorKind::InvalidSolution => "invalid_solution",
+        }
+    }
+
+    /// The BatchApi::Vroom exception class raised for vroom's error codes
+    pub fn exception_class(&self) -> Result<ExceptionClass, magnus::Error> {
+        let name = match self {
+            VroomErrorKind::Input => "InputError",
+            VroomErrorKind::Routing => "RoutingError",
+            VroomErrorKind::Internal => "InternalError",
+            VroomErrorKind::InvalidSolution => "Error",
+        };
+        vroom_exception_class(name)
+    }
+}
+
+/// Looks up one of the exception classes defined under BatchApi::Vroom
+pub fn vroom_exception_class(name: &str) -> Result<ExceptionClass, magnus::Error> {
+    let batch_api: RModule = class::object().const_get("BatchApi")?;
+    let vroom: RModule = batch_api.const_get("Vroom")?;
+    vroom.const_get(name)
 }
 
-impl Response {
-    /// consumes self and returns a ruby convertable rust type
-    pub fn into_hashmap(self) -> HashMap<String, String> {
-        let mut rbarray_convertable_hashmap: HashMap<String, String> = HashMap::with_capacity(1);
-        // Insert hash for all fields on Request
-        rbarray_convertable_hashmap.insert(String::from("body"), self.body);
-        rbarray_convertable_hashmap.insert(
-            String::from("http_status_code"),
-            self.http_status_code.to_string(),
-        );
-        rbarray_convertable_hashmap
+impl VroomResponse {
+    /// Parses the response body if the batch asked for solutions
+    pub fn parse(response: Response, options: &VroomOptions) -> Self {
+        let solution = match &response.outcome {
+            Outcome::Http {
+                http_status_code,
+                body,
+                ..
+            } if options.parse_solutions => Solution::parse(*http_status_code, body),
+            _ => None,
+        };
+        VroomResponse { response, solution }
+    }
+
+    /// vroom's own error for the request, if it answered with one
+    pub fn vroom_error(&self) -> Option<(VroomErrorKind, String)> {
+        match &self.solution {
+            Some(Ok(solution)) => solution
+                .error_kind()
+                .map(|error_kind| (error_kind, solution.error.clone().unwrap_or_default())),
+            _ => None,
+        }
+    }
+
+    /// consumes self and returns the ruby hash handed back for the request
+    pub fn into_rhash(self) -> Result<RHash, magnus::Error> {
+        let rb_hash = self.response.into_rhash()?;
+
+        // only there when the batch asked for parsed solutions
+        match self.solution {
+            Some(Ok(solution)) => {
+                rb_hash.aset("code", solution.code)?;
+                match solution.error_kind() {
+                    Some(error_kind) => {
+                        rb_hash.aset("error_kind", error_kind.as_str())?;
+                        rb_hash.aset("error_message", solution.error.unwrap_or_default())?;
+                    }
+                    None => rb_hash.aset("solution", solution.into_ruby()?)?,
+                }
+            }
+            Some(Err(message)) => {
+                rb_hash.aset("error_kind", VroomErrorKind::InvalidSolution.as_str())?;
+                rb_hash.aset("error_message", message)?;
+            }
+            None => {}
+        }
+        Ok(rb_hash)
     }
 }
@@ -0,0 +1,171 @@
+// Rust types for vroom's output, see
+// https://github.com/VROOM-Project/vroom/blob/master/docs/API.md#output
+// Fields vroom leaves out depending on the problem (distance, geometry etc.) are optional
+
+use magnus::Value;
+use serde::{Deserialize, Serialize};
+
+use crate::batch::response::json_into_ruby;
+
+use super::problem::Location;
+use super::response::VroomErrorKind;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Solution {
+    // 0 is a solution, anything else comes with an error message
+    pub code: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<Summary>,
+    #[serde(default)]
+    pub unassigned: Vec<Unassigned>,
+    #[serde(default)]
+    pub routes: Vec<Route>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Summary {
+    #[serde(default)]
+    pub cost: u64,
+    // number of routes and unassigned tasks
+    #[serde(default)]
+    pub routes: u32,
+    #[serde(default)]
+    pub unassigned: u32,
+    #[serde(default)]
+    pub setup: u64,
+    #[serde(default)]
+    pub service: u64,
+    #[serde(default)]
+    pub duration: u64,
+    #[serde(default)]
+    pub waiting_time: u64,
+    #[serde(default)]
+    pub priority: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance: Option<u64>,
+    #[serde(default)]
+    pub violations: Vec<Violation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub delivery: Vec<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pickup: Vec<i64>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Route {
+    pub vehicle: u64,
+    #[serde(default)]
+    pub steps: Vec<Step>,
+    #[serde(default)]
+    pub cost: u64,
+    #[serde(default)]
+    pub setup: u64,
+    #[serde(default)]
+    pub service: u64,
+    #[serde(default)]
+    pub duration: u64,
+    #[serde(default)]
+    pub waiting_time: u64,
+    #[serde(default)]
+    pub priority: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance: Option<u64>,
+    #[serde(default)]
+    pub violations: Vec<Violation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub delivery: Vec<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pickup: Vec<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // encoded polyline, only there when the problem asked for geometry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geometry: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Step {
+    // start, job, pickup, delivery, break or end
+    #[serde(rename = "type")]
+    pub step_type: String,
+    #[serde(default)]
+    pub arrival: u64,
+    #[serde(default)]
+    pub duration: u64,
+    #[serde(default)]
+    pub setup: u64,
+    #[serde(default)]
+    pub service: u64,
+    #[serde(default)]
+    pub waiting_time: u64,
+    #[serde(default)]
+    pub violations: Vec<Violation>,
+    // the job, shipment step or break id, start and end steps don't have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location_index: Option<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub load: Vec<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Unassigned {
+    pub id: u64,
+    // job, pickup or delivery
+    #[serde(rename = "type", default)]
+    pub task_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location_index: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Violation {
+    // e.g. delay, lead_time, load, skills
+    pub cause: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+}
+
+impl Solution {
+    /// Parses a vroom response body. Error statuses with a body that isn't vroom's
+    /// (e.g. a proxy's 502 page) give None so the status speaks for itself
+    pub fn parse(http_status_code: u16, body: &str) -> Option<Result<Self, String>> {
+        match serde_json::from_str::<Solution>(body) {
+            Ok(solution) => Some(Ok(solution)),
+            Err(_) if !(200..300).contains(&http_status_code) => None,
+            Err(err) => Some(Err(format!("vroom response is not a solution: {}", err))),
+        }
+    }
+
+    /// vroom's error codes, None when the problem was solved
+    pub fn error_kind(&self) -> Option<VroomErrorKind> {
+        match self.code {
+            0 => None,
+            2 => Some(VroomErrorKind::Input),
+            3 => Some(VroomErrorKind::Routing),
+            // 1, and anything newer than we know about
+            _ => Some(VroomErrorKind::Internal),
+        }
+    }
+
+    /// The solution as nested ruby hashes and arrays with string keys, like JSON.parse gives
+    pub fn into_ruby(self) -> Result<Value, magnus::Error> {
+        let json = serde_json::to_value(self).map_err(|err| {
+            magnus::Error::new(magnus::exception::runtime_error(), err.to_string())
+        })?;
+        json_into_ruby(json)
+    }
+}
@@ -1,8 +1,36 @@
 # frozen_string_literal: true
 
+require 'fileutils'
 require 'json'
+require 'openssl'
+require 'socket'
+require 'tmpdir'
+require 'zlib'
 
 RSpec.describe BatchApi do
+  # A tiny http server for vroom requests, answers each one with 200
+  # and whatever the block returns for the request's json body
+  def respond_to_requests(server)
+    Thread.new do
+      loop do
+        begin
+          socket = server.accept
+        rescue OpenSSL::SSL::SSLError
+          # a client that didn't trust our certificate
+          next
+        end
+        # read the request headers then the json body
+        content_length = 0
+        while (line = socket.gets) && line != "\r\n"
+          content_length = line.split(':').last.to_i if line.downcase.start_with?('content-length')
+        end
+        body = yield socket.read(content_length)
+        socket.write("HTTP/1.1 200 OK\r\nContent-Length: #{body.bytesize}\r\nConnection: close\r\n\r\n#{body}")
+        socket.close
+      end
+    end
+  end
+
   it "has a version number" do
     expect(BatchApi::VERSION).not_to be nil
   end
@@ -38,6 +66,903 @@ RSpec.describe BatchApi do
     end
   end
 
+  describe BatchApi::Vroom::Problem do
+    let(:problem) do
+      BatchApi::Vroom::Problem.new.tap do |p|
+        p.add_vehicle(id: 1, start: [-1.08, 53.96], capacity: [4], time_window: [0, 3600])
+        p.add_job(id: 1, location: [-1.09, 53.95], delivery: [1], time_windows: [[0, 1800]])
+      end
+    end
+
+    it 'serializes to vroom json, leaving out fields that are not set' do
+      json = JSON.parse(problem.to_json)
+
+      expect(json['vehicles']).to eq([{ 'id' => 1, 'start' => [-1.08, 53.96], 'capacity' => [4], 'time_window' => [0, 3600] }])
+      expect(json['jobs']).to eq([{ 'id' => 1, 'location' => [-1.09, 53.95], 'delivery' => [1], 'time_windows' => [[0, 1800]] }])
+      expect(json).not_to have_key('shipments')
+    end
+
+    it 'can be used directly as a request body' do
+      expect(String.try_convert(problem)).to eq(problem.to_json)
+    end
+
+    it 'validates fields as they are added' do
+      expect { problem.add_job(id: 2, location: [-1.09]) }.to raise_error(ArgumentError, /\[lon, lat\]/)
+      expect { problem.add_job(id: 2, location: [-1.09, 53.95], time_window: [0, 10]) }.to raise_error(ArgumentError, /unknown key time_window/)
+      expect { problem.add_job(id: 2, location: [-1.09, 53.95], delivery: ['one']) }.to raise_error(TypeError, /delivery/)
+      expect { problem.add_job(id: 2, location: [-1.09, 53.95], priority: 101) }.to raise_error(ArgumentError)
+      expect { problem.add_job(id: 1, location: [-1.09, 53.95]) }.to raise_error(ArgumentError, /already been added/)
+      expect { problem.add_job(id: 2) }.to raise_error(ArgumentError, /location/)
+    end
+
+    it 'prints and inspects problems that are still being built without raising' do
+      empty = BatchApi::Vroom::Problem.new
+
+      expect(empty.to_s).to eq('{}')
+      expect("#{empty}").to eq('{}')
+      expect(problem.inspect).to eq('#<BatchApi::Vroom::Problem vehicles=1 jobs=1 shipments=0>')
+    end
+
+    it 'validates the whole problem when serializing' do
+      expect { BatchApi::Vroom::Problem.new.to_json }.to raise_error(ArgumentError, /vehicle/)
+
+      problem.add_job(id: 2, location: [-1.09, 53.95], delivery: [1, 1])
+      expect { problem.to_json }.to raise_error(ArgumentError, /dimensions/)
+    end
+
+    it 'needs location indices alongside matrices' do
+      problem.add_matrices('car', durations: [[0, 60], [60, 0]])
+      expect { problem.to_json }.to raise_error(ArgumentError, /location_index/)
+      expect { problem.add_matrices('car', durations: [[0, 60]]) }.to raise_error(ArgumentError, /square/)
+    end
+  end
+
+  describe BatchApi::Vroom::Client do
+    describe '#new' do
+      it 'builds a client with or without options' do
+        expect(BatchApi::Vroom::Client.new).to be_a BatchApi::Vroom::Client
+        client = BatchApi::Vroom::Client.new(
+          base_url: 'http://localhost:3000', pool_size: 4, connect_timeout: 1, request_timeout: 30
+        )
+        expect(client).to be_a BatchApi::Vroom::Client
+      end
+
+      it 'raises argument errors for invalid timeouts' do
+        expect { BatchApi::Vroom::Client.new(connect_timeout: 0) }.to raise_error(ArgumentError)
+      end
+    end
+
+    describe '#batch_send' do
+      # nothing listens on port 1 so the connection is refused
+      let(:client) { BatchApi::Vroom::Client.new(base_url: 'http://127.0.0.1:1') }
+
+      it 'returns an empty array for no requests' do
+        expect(client.batch_send([])).to eq([])
+      end
+
+      it 'sends requests to the base url instead of VROOM_URL' do
+        responses = client.batch_send([{ 'body' => '{}' }] * 2, max_concurrency: 1)
+        expect(responses.map { |r| r['error_kind'] }).to eq(%w[connect connect])
+      end
+
+      it 'can be reused across batches' do
+        2.times { expect(client.batch_send([{ 'body' => '{}' }]).size).to be 1 }
+      end
+
+      it 'reports the endpoint each request went to' do
+        responses = client.batch_send([{ 'body' => '{}' }])
+        expect(responses.first['endpoint']).to eq('http://127.0.0.1:1')
+      end
+    end
+
+    context 'when vroom answers' do
+      # echoes each request body back, so the body is the vroom response we want
+      let(:server) { TCPServer.new('127.0.0.1', 0) }
+      let(:client_url) { "http://127.0.0.1:#{server.addr[1]}" }
+      let(:client) { BatchApi::Vroom::Client.new(base_url: client_url) }
+      let(:solution) do
+        {
+          code: 0,
+          summary: { cost: 120, routes: 1, unassigned: 1, duration: 120, violations: [] },
+          unassigned: [{ id: 2, type: 'job', location: [-1.1, 53.9] }],
+          routes: [{ vehicle: 1, cost: 120, duration: 120, steps: [{ type: 'start', arrival: 0 }, { type: 'job', id: 1, arrival: 60 }] }]
+        }
+      end
+
+      # how many requests made it to the server
+      let(:sent) { [] }
+
+      around do |example|
+        responder = respond_to_requests(server) { |body| (sent << body).last }
+        example.run
+      ensure
+        responder.kill
+        server.close
+      end
+
+      it 'reports the status, timing and index of each request' do
+        responses = client.batch_send([{ 'body' => '{"code":0}' }] * 2, response_headers: ['Content-Length'])
+
+        expect(responses.map { |r| r['index'] }).to eq([0, 1])
+        expect(responses.first).to include('http_status_code' => 200, 'attempts' => 1, 'endpoint' => client_url)
+        expect(responses.first['headers']).to eq('content-length' => '10')
+        expect(responses.first['latency_ms']).to be_a(Float).and be > 0
+      end
+
+      it 'yields responses to a block as they finish' do
+        # accepts connections but never answers
+        silent = TCPServer.new('127.0.0.1', 0)
+        requests = [{ 'url' => "http://127.0.0.1:#{silent.addr[1]}", 'body' => '{}' }, { 'body' => '{"code":0}' }]
+
+        yielded = []
+        result = client.batch_send(requests, request_timeout: 0.5) do |index, response|
+          yielded << [index, response['http_status_code'] || response['error_kind']]
+        end
+
+        expect(result).to be_nil
+        expect(yielded).to eq([[1, 200], [0, 'timeout']])
+      ensure
+        silent&.close
+      end
+
+      it 'stops the batch when the block raises' do
+        expect { client.batch_send([{ 'body' => '{}' }] * 3) { raise 'stop' } }.to raise_error('stop')
+        expect(client.batch_send([{ 'body' => '{}' }]).size).to be 1
+      end
+
+      it 'caches responses and collapses identical requests' do
+        client = BatchApi::Vroom::Client.new(base_url: client_url, cache: { max_entries: 10, ttl: 60 })
+        # same problem with its keys in a different order
+        requests = [
+          { 'body' => '{"code":0,"routes":[]}' },
+          { 'body' => '{ "routes": [], "code": 0 }' },
+          { 'body' => '{"code":0,"routes":[],"unassigned":[]}' }
+        ]
+
+        responses = client.batch_send(requests)
+        expect(responses.map { |r| r['cache_hit'] }).to eq([false, true, false])
+        expect(responses[1]['body']).to eq(responses[0]['body'])
+        expect(sent.size).to be 2
+
+        responses = client.batch_send(requests)
+        expect(responses.map { |r| r['cache_hit'] }).to eq([true, true, true])
+        expect(sent.size).to be 2
+      end
+
+      it 'keeps responses in an on disk store across clients' do
+        Dir.mktmpdir do |dir|
+          requests = [{ 'body' => '{"code":0,"routes":[]}' }]
+          BatchApi::Vroom::Client.new(base_url: client_url, store: { path: dir }).batch_send(requests)
+          expect(Dir.glob(File.join(dir, '*', '*.json.gz')).size).to be 1
+
+          # a fresh client, as if after a restart
+          client = BatchApi::Vroom::Client.new(base_url: client_url, store: { path: dir, max_age: 3600 })
+          response = client.batch_send(requests).first
+          expect(response).to include('cache_hit' => true, 'http_status_code' => 200, 'body' => '{"code":0,"routes":[]}')
+          expect(sent.size).to be 1
+        end
+      end
+
+      it "leaves other writers' temporary files alone when sweeping the store" do
+        Dir.mktmpdir do |dir|
+          FileUtils.mkdir_p(File.join(dir, 'ab'))
+          in_flight = File.join(dir, 'ab', 'abc.123.0.tmp')
+          File.write(in_flight, 'x' * 100)
+          File.write(File.join(dir, 'ab', 'abd.json.gz'), 'x' * 100)
+
+          # opening a store sweeps it straight away
+          BatchApi::Vroom::Client.new(base_url: client_url, store: { path: dir, max_bytes: 1 })
+
+          expect(File.exist?(in_flight)).to be true
+          expect(File.exist?(File.join(dir, 'ab', 'abd.json.gz'))).to be false
+        end
+      end
+
+      it 'raises argument errors for invalid store options' do
+        expect { BatchApi::Vroom::Client.new(store: {}) }.to raise_error(ArgumentError)
+        expect { BatchApi::Vroom::Client.new(store: { path: Dir.tmpdir, max_bytes: 0 }) }.to raise_error(ArgumentError)
+      end
+
+      it 'records batches to a cassette and replays them without a server' do
+        Dir.mktmpdir do |dir|
+          cassette = File.join(dir, 'plan.ndjson')
+          requests = [{ 'body' => '{"code":0,"routes":[]}' }, { 'body' => '{"code":2,"error":"Invalid"}' }]
+          recorded = client.batch_send(requests, cassette: { path: cassette, mode: :record })
+
+          lines = File.readlines(cassette).map { |line| JSON.parse(line) }
+          expect(lines.size).to be 2
+          expect(lines.first).to include('http_status_code' => 200, 'body' => '{"code":0,"routes":[]}')
+          expect(lines.first['latency_ms']).to be_a(Float)
+
+          # same problems with their keys in a different order
+          replayed = BatchApi::Vroom.batch_send_api_requests(
+            [{ 'body' => '{ "routes": [], "code": 0 }' }, requests.last],
+            cassette: { path: cassette, mode: 'replay' }, parse_solutions: true
+          )
+          expect(replayed.map { |r| r['body'] }).to eq(recorded.map { |r| r['body'] })
+          expect(replayed.map { |r| r['error_kind'] }).to eq([nil, 'vroom_input'])
+          expect(replayed.first['latency_ms']).to be_within(0.01).of(recorded.first['latency_ms'])
+          # nothing more was sent
+          expect(sent.size).to be 2
+
+          expect do
+            BatchApi::Vroom.batch_send_api_requests([{ 'body' => '{}' }], cassette: { path: cassette, mode: :replay })
+          end.to raise_error(BatchApi::Vroom::CassetteError, /request 0/)
+        end
+      end
+
+      it 'raises argument errors for invalid cassette options' do
+        expect { client.batch_send([], cassette: { mode: :record }) }.to raise_error(ArgumentError)
+        expect { client.batch_send([], cassette: { path: 'x.ndjson', mode: :rewind }) }.to raise_error(ArgumentError)
+      end
+
+      it 'does not cache without a cache option' do
+        responses = client.batch_send([{ 'body' => '{"code":0}' }] * 2)
+        expect(responses.map { |r| r['cache_hit'] }).to eq([false, false])
+        expect(sent.size).to be 2
+      end
+
+      it 'raises argument errors for invalid cache options' do
+        expect { BatchApi::Vroom::Client.new(cache: { max_entries: 0 }) }.to raise_error(ArgumentError)
+        expect { BatchApi::Vroom::Client.new(cache: { ttl: -1 }) }.to raise_error(ArgumentError)
+      end
+
+      it 'parses solutions into ruby hashes' do
+        response = client.batch_send([{ 'body' => solution.to_json }], parse_solutions: true).first
+
+        expect(response['code']).to eq(0)
+        expect(response['solution']['summary']).to include('cost' => 120, 'routes' => 1, 'unassigned' => 1)
+        expect(response['solution']['routes'].first['steps'].map { |step| step['type'] }).to eq(%w[start job])
+        expect(response['solution']['unassigned']).to eq([{ 'id' => 2, 'type' => 'job', 'location' => [-1.1, 53.9] }])
+      end
+
+      it 'sorts out vroom error codes' do
+        requests = [1, 2, 3].map { |code| { 'body' => { code: code, error: "error #{code}" }.to_json } }
+        responses = client.batch_send(requests, parse_solutions: true)
+
+        expect(responses.map { |r| r['error_kind'] }).to eq(%w[vroom_internal vroom_input vroom_routing])
+        expect(responses.map { |r| r['error_message'] }).to eq(['error 1', 'error 2', 'error 3'])
+        expect(responses.map { |r| r['code'] }).to eq([1, 2, 3])
+      end
+
+      it 'flags successful responses that are not solutions' do
+        response = client.batch_send([{ 'body' => '"not a solution"' }], parse_solutions: true).first
+        expect(response['error_kind']).to eq('invalid_solution')
+      end
+
+      it 'raises vroom errors as exceptions when asked to' do
+        requests = [{ 'body' => solution.to_json }, { 'body' => { code: 2, error: 'Invalid vehicles' }.to_json }]
+
+        expect { client.batch_send(requests, raise_vroom_errors: true) }
+          .to raise_error(BatchApi::Vroom::InputError, /request 1: Invalid vehicles/)
+        expect(BatchApi::Vroom::InputError.ancestors).to include(BatchApi::Vroom::Error, BatchApi::Error)
+      end
+    end
+
+    context 'over tls' do
+      # a self signed certificate for 127.0.0.1 that's its own CA
+      let(:key) { OpenSSL::PKey::RSA.new(2048) }
+      let(:certificate) do
+        OpenSSL::X509::Certificate.new.tap do |cert|
+          cert.version = 2
+          cert.serial = 1
+          cert.subject = cert.issuer = OpenSSL::X509::Name.parse('/CN=127.0.0.1')
+          cert.public_key = key.public_key
+          cert.not_before = Time.now - 60
+          cert.not_after = Time.now + 3600
+          extensions = OpenSSL::X509::ExtensionFactory.new(cert, cert)
+          cert.add_extension(extensions.create_extension('subjectAltName', 'IP:127.0.0.1'))
+          cert.add_extension(extensions.create_extension('basicConstraints', 'CA:TRUE', true))
+          cert.sign(key, OpenSSL::Digest.new('SHA256'))
+        end
+      end
+      let(:tcp_server) { TCPServer.new('127.0.0.1', 0) }
+      let(:server) do
+        context = OpenSSL::SSL::SSLContext.new
+        context.cert = certificate
+        context.key = key
+        OpenSSL::SSL::SSLServer.new(tcp_server, context)
+      end
+      let(:client_url) { "https://127.0.0.1:#{tcp_server.addr[1]}" }
+
+      around do |example|
+        responder = respond_to_requests(server) { '{"code":0}' }
+        example.run
+      ensure
+        responder.kill
+        server.close
+      end
+
+      it 'reports untrusted certificates as their own error kind' do
+        response = BatchApi::Vroom::Client.new(base_url: client_url).batch_send([{ 'body' => '{}' }]).first
+        expect(response['error_kind']).to eq('certificate')
+      end
+
+      it 'trusts a CA bundle' do
+        Dir.mktmpdir do |dir|
+          File.write(File.join(dir, 'ca.pem'), certificate.to_pem)
+          client = BatchApi::Vroom::Client.new(base_url: client_url, tls: { ca_file: File.join(dir, 'ca.pem'), min_version: '1.2' })
+          expect(client.batch_send([{ 'body' => '{}' }]).first['http_status_code']).to eq(200)
+        end
+      end
+
+      it 'can skip verification in development' do
+        client = nil
+        expect { client = BatchApi::Vroom::Client.new(base_url: client_url, tls: { insecure: true }) }
+          .to output(/not verifying tls certificates/).to_stderr
+        expect(client.batch_send([{ 'body' => '{}' }]).first['http_status_code']).to eq(200)
+      end
+
+      it 'raises for invalid tls options' do
+        expect { BatchApi::Vroom::Client.new(tls: { min_version: '1.4' }) }.to raise_error(ArgumentError)
+        expect { BatchApi::Vroom::Client.new(tls: { cert_file: 'client.pem' }) }.to raise_error(ArgumentError)
+        expect { BatchApi::Vroom::Client.new(tls: { ca_file: '/no/such/ca.pem' }) }.to raise_error(IOError)
+      end
+    end
+
+    context 'through a proxy' do
+      # the mock answers whatever it's sent, so it stands in for the proxy too
+      let(:proxy) { BatchApi::Vroom::MockServer.start }
+
+      after { proxy.stop }
+
+      it 'sends requests through the proxy unless the host is excluded' do
+        proxied = BatchApi::Vroom::Client.new(base_url: 'http://vroom.internal:3000', proxy: proxy.url)
+        expect(proxied.batch_send([{ 'body' => '{"via":"proxy"}' }]).first['http_status_code']).to eq(200)
+        expect(proxy.requests).to eq(['{"via":"proxy"}'])
+
+        excluded = BatchApi::Vroom::Client.new(
+          base_url: 'http://127.0.0.1:1', proxy: { url: proxy.url, no_proxy: ['127.0.0.1'] }
+        )
+        expect(excluded.batch_send([{ 'body' => '{}' }]).first['error_kind']).to eq('connect')
+        expect(proxy.requests.size).to be 1
+      end
+
+      it 'raises argument errors for a proxy without a url' do
+        expect { BatchApi::Vroom::Client.new(proxy: { no_proxy: 'localhost' }) }.to raise_error(ArgumentError)
+      end
+    end
+
+    context 'with several endpoints' do
+      # nothing listens on either port so every request is refused
+      let(:endpoints) { ['http://127.0.0.1:1', 'http://127.0.0.1:2'] }
+
+      it 'spreads requests across them round robin' do
+        client = BatchApi::Vroom::Client.new(endpoints: endpoints, eject_after: 100)
+        responses = client.batch_send([{ 'body' => '{}' }] * 4, max_concurrency: 1)
+        expect(responses.map { |r| r['endpoint'] }.tally).to eq(endpoints.to_h { |e| [e, 2] })
+      end
+
+      context 'when one of them is up' do
+        # answers every request with an empty vroom solution
+        let(:server) { TCPServer.new('127.0.0.1', 0) }
+        let(:endpoints) { ['http://127.0.0.1:1', "http://127.0.0.1:#{server.addr[1]}"] }
+
+        around do |example|
+          responder = respond_to_requests(server) { '{"code":0}' }
+          example.run
+        ensure
+          responder.kill
+          server.close
+        end
+
+        it 'ejects endpoints that keep failing' do
+          client = BatchApi::Vroom::Client.new(endpoints: endpoints, eject_after: 1, eject_for: 60)
+          client.batch_send([{ 'body' => '{}' }], max_concurrency: 1)
+          # the first endpoint is out of rotation so everything goes to the second
+          responses = client.batch_send([{ 'body' => '{}' }] * 3, max_concurrency: 1)
+          expect(responses.map { |r| r['endpoint'] }.uniq).to eq([endpoints.last])
+          expect(responses.map { |r| r['http_status_code'] }.uniq).to eq([200])
+        end
+      end
+
+      it 'accepts least_in_flight balancing' do
+        client = BatchApi::Vroom::Client.new(endpoints: endpoints, balance: :least_in_flight)
+        expect(client.batch_send([{ 'body' => '{}' }] * 2).size).to be 2
+      end
+
+      it 'raises argument errors for invalid endpoint options' do
+        expect { BatchApi::Vroom::Client.new(endpoints: ['not a url']) }.to raise_error(ArgumentError)
+        expect { BatchApi::Vroom::Client.new(endpoints: endpoints, balance: :random) }.to raise_error(ArgumentError)
+        expect { BatchApi::Vroom::Client.new(endpoints: endpoints, eject_after: 0) }.to raise_error(ArgumentError)
+      end
+    end
+  end
+
+  describe BatchApi::Vroom::MockServer do
+    let(:server) { BatchApi::Vroom::MockServer.start }
+    let(:client) { BatchApi::Vroom::Client.new(base_url: server.url) }
+
+    after { server.stop }
+
+    it 'answers with an empty solution and keeps the requests it got' do
+      response = client.batch_send([{ 'body' => '{"jobs":[]}' }], parse_solutions: true).first
+
+      expect(response).to include('http_status_code' => 200, 'code' => 0)
+      expect(response['solution']).to include('routes' => [], 'unassigned' => [])
+      expect(server.requests).to eq(['{"jobs":[]}'])
+      expect(server.url).to eq("http://127.0.0.1:#{server.port}")
+    end
+
+    it 'answers in order with queued responses then the default' do
+      server.enqueue(code: 2, error: 'Invalid vehicles')
+      server.enqueue(status: 503, body: 'overloaded', headers: { 'x-mock' => 'yes' })
+
+      responses = client.batch_send([{ 'body' => '{}' }] * 3, max_concurrency: 1, response_headers: ['x-mock'])
+
+      expect(responses.map { |r| r['http_status_code'] }).to eq([400, 503, 200])
+      expect(responses[0]['body']).to eq({ code: 2, error: 'Invalid vehicles' }.to_json)
+      expect(responses[1]).to include('body' => 'overloaded', 'headers' => { 'x-mock' => 'yes' })
+    end
+
+    it 'delays or drops requests whose body matches' do
+      server.on('slow', delay: 1)
+      server.on('dropped', drop: true)
+      requests = [{ 'body' => '{"slow":true}' }, { 'body' => '{"dropped":true}' }, { 'body' => '{}' }]
+
+      responses = client.batch_send(requests, request_timeout: 0.2)
+
+      expect(responses.map { |r| r['error_kind'] }).to eq(['timeout', 'connect', nil])
+      expect(server.requests.size).to be 3
+    end
+
+    it 'returns partial results at the batch timeout with the unfinished requests flagged' do
+      server.on('slow', delay: 5)
+      requests = [{ 'body' => '{}' }, { 'body' => '{"slow":true}' }]
+
+      responses = client.batch_send(requests, batch_timeout: 0.5)
+
+      expect(responses.map { |r| r['index'] }).to eq([0, 1])
+      expect(responses[0]).to include('http_status_code' => 200, 'attempts' => 1)
+      expect(responses[1]).to include('error_kind' => 'timeout', 'attempts' => 0)
+      expect(responses[1]['error_message']).to include('batch timeout')
+    end
+
+    it 'lets retries get past a dropped connection' do
+      server.enqueue(drop: true)
+      response = client.batch_send([{ 'body' => '{}' }], retry: { max_attempts: 2, base_delay: 0.01 }).first
+      expect(response).to include('http_status_code' => 200, 'attempts' => 2)
+    end
+
+    it 'lets retries get past a connection reset while the body is read' do
+      raw = TCPServer.new('127.0.0.1', 0)
+      # the first answer promises more body than it sends before the connection goes
+      answers = ["HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n{\"code\"", "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n{\"code\":0}"]
+      responder = Thread.new do
+        answers.each do |answer|
+          socket = raw.accept
+          content_length = 0
+          while (line = socket.gets) && line != "\r\n"
+            content_length = line.split(':').last.to_i if line.downcase.start_with?('content-length')
+          end
+          socket.read(content_length)
+          socket.write(answer)
+          socket.close
+        end
+      end
+
+      response = client.batch_send([{ 'url' => "http://127.0.0.1:#{raw.addr[1]}", 'body' => '{}' }], retry: { max_attempts: 2, base_delay: 0.01 }).first
+
+      expect(response).to include('http_status_code' => 200, 'attempts' => 2, 'body' => '{"code":0}')
+    ensure
+      responder&.kill
+      raw&.close
+    end
+
+    it 'compresses request bodies over the threshold' do
+      raw = TCPServer.new('127.0.0.1', 0)
+      received = []
+      responder = respond_to_requests(raw) { |body| (received << body) && '{"code":0}' }
+      large = { jobs: Array.new(200) { |id| { id: id, location: [-1.08, 53.96] } } }.to_json
+      requests = [large, '{}'].map { |body| { 'url' => "http://127.0.0.1:#{raw.addr[1]}", 'body' => body } }
+
+      client.batch_send(requests, compression: { encoding: :gzip, min_bytes: 1024 }, max_concurrency: 1)
+
+      expect(Zlib.gunzip(received.first)).to eq(large)
+      expect(received.last).to eq('{}')
+    ensure
+      responder&.kill
+      raw&.close
+    end
+
+    it 'sends brotli bodies and decompresses responses' do
+      server.enqueue(body: '{"code":3,"error":"Unroutable"}', encoding: :br)
+      response = client.batch_send([{ 'body' => '{"brotli":true}' }], compression: { encoding: :br, min_bytes: 0 }).first
+
+      expect(response['body']).to eq('{"code":3,"error":"Unroutable"}')
+      expect(server.requests).to eq(['{"brotli":true}'])
+    end
+
+    it 'sends client and per request headers with auth' do
+      client = BatchApi::Vroom::Client.new(base_url: server.url, headers: { 'x-tenant' => 'acme' }, auth: { bearer: 'abc123' })
+      requests = [{ 'body' => '{}' }, { 'body' => '{}', 'headers' => { 'x-tenant' => 'other', 'authorization' => 'Token xyz' } }]
+
+      client.batch_send(requests, max_concurrency: 1)
+
+      expect(server.request_headers[0]).to include('x-tenant' => 'acme', 'authorization' => 'Bearer abc123')
+      expect(server.request_headers[1]).to include('x-tenant' => 'other', 'authorization' => 'Token xyz')
+    end
+
+    it 'refreshes auth without rebuilding the client' do
+      client = BatchApi::Vroom::Client.new(base_url: server.url, auth: { bearer: 'expired' })
+      client.auth = { username: 'planner', password: 'hunter2' }
+      client.batch_send([{ 'body' => '{}' }])
+      client.auth = nil
+      client.batch_send([{ 'body' => '{}' }])
+
+      expect(server.request_headers[0]['authorization']).to eq("Basic #{['planner:hunter2'].pack('m0')}")
+      expect(server.request_headers[1]).not_to have_key('authorization')
+    end
+
+    it "doesn't serve responses cached under credentials the client has swapped out" do
+      client = BatchApi::Vroom::Client.new(base_url: server.url, auth: { bearer: 'abc123' }, cache: { max_entries: 10, ttl: 60 })
+      client.batch_send([{ 'body' => '{}' }])
+      client.auth = { bearer: 'def456' }
+
+      expect(client.batch_send([{ 'body' => '{}' }]).first['cache_hit']).to be false
+      expect(server.request_headers.map { |headers| headers['authorization'] }).to eq(['Bearer abc123', 'Bearer def456'])
+    end
+
+    it 'redacts secrets from error messages' do
+      client = BatchApi::Vroom::Client.new(auth: { bearer: 'abc123' })
+      response = client.batch_send([{ 'url' => 'http://127.0.0.1:1/?access_token=abc123', 'body' => '{}' }]).first
+
+      expect(response['error_message']).to include('[REDACTED]')
+      expect(response['error_message']).not_to include('abc123')
+    end
+
+    it 'raises argument errors for invalid headers and auth' do
+      expect { BatchApi::Vroom::Client.new(auth: {}) }.to raise_error(ArgumentError)
+      expect { BatchApi::Vroom::Client.new(headers: { 'x-api-key' => "abc\n123" }) }
+        .to raise_error(ArgumentError) { |error| expect(error.message).not_to include('abc') }
+      expect { client.batch_send([{ 'body' => '{}', 'headers' => { 'bad header' => 'x' } }]) }.to raise_error(ArgumentError)
+    end
+
+    it 'raises argument errors for invalid compression options' do
+      expect { client.batch_send([], compression: { encoding: :zstd }) }.to raise_error(ArgumentError)
+      expect { client.batch_send([], compression: { encoding: :gzip, level: 10 }) }.to raise_error(ArgumentError)
+    end
+
+    it 'holds requests back to the rate limit and reports how long they queued' do
+      client = BatchApi::Vroom::Client.new(base_url: server.url, rate_limit: { requests_per_second: 10 })
+
+      responses = client.batch_send([{ 'body' => '{}' }] * 3, max_concurrency: 3)
+
+      # the first goes straight away, the others queue behind it a tenth of a second apart
+      first, second, third = responses.map { |r| r['queue_wait_ms'] }.sort
+      expect(responses.map { |r| r['http_status_code'] }).to eq([200] * 3)
+      expect(second).to be >= 50
+      expect(third - second).to be >= 50
+    end
+
+    it 'limits endpoints listed by url on their own' do
+      client = BatchApi::Vroom::Client.new(
+        base_url: server.url,
+        rate_limit: { requests_per_second: 1, endpoints: { "#{server.url}/" => { requests_per_second: 100, burst: 3 } } }
+      )
+      responses = client.batch_send([{ 'body' => '{}' }] * 3)
+      # a second apart under the top level limit
+      expect(responses.map { |r| r['queue_wait_ms'] }.max).to be < 1000
+    end
+
+    it 'opens the circuit after repeated failures and fails fast' do
+      client = BatchApi::Vroom::Client.new(base_url: server.url, circuit_breaker: { failure_threshold: 2, reset_timeout: 60 })
+      2.times { server.enqueue(status: 503) }
+
+      responses = client.batch_send([{ 'body' => '{}' }] * 3, max_concurrency: 1)
+
+      expect(responses.map { |r| r['http_status_code'] || r['error_kind'] }).to eq([503, 503, 'circuit_open'])
+      expect(responses.last).to include('endpoint' => server.url, 'attempts' => 0)
+      expect(server.requests.size).to be 2
+      expect(client.circuits[server.url]).to include('state' => 'open', 'consecutive_failures' => 2)
+      expect(client.circuits[server.url]['retry_in']).to be > 0
+    end
+
+    it 'closes the circuit again once a trial succeeds after the reset timeout' do
+      client = BatchApi::Vroom::Client.new(base_url: server.url, circuit_breaker: { failure_threshold: 2, reset_timeout: 0.05 })
+      2.times { server.enqueue(status: 503) }
+      client.batch_send([{ 'body' => '{}' }] * 2, max_concurrency: 1)
+      expect(client.circuits[server.url]['state']).to eq('open')
+
+      # waits on the circuit rather than a set time
+      sleep 0.01 until client.circuits[server.url]['retry_in'].zero?
+
+      expect(client.batch_send([{ 'body' => '{}' }]).first['http_status_code']).to be 200
+      expect(client.circuits[server.url]).to include('state' => 'closed', 'consecutive_failures' => 0)
+    end
+
+    it 'balances away from endpoints with an open circuit' do
+      client = BatchApi::Vroom::Client.new(
+        endpoints: ['http://127.0.0.1:1', server.url], eject_after: 100, circuit_breaker: { failure_threshold: 1 }
+      )
+      responses = client.batch_send([{ 'body' => '{}' }] * 4, max_concurrency: 1)
+
+      expect(responses.map { |r| r['endpoint'] }).to eq(['http://127.0.0.1:1'] + [server.url] * 3)
+      expect(client.circuits.transform_values { |c| c['state'] }).to eq('http://127.0.0.1:1' => 'open', server.url => 'closed')
+    end
+
+    it 'raises argument errors for invalid circuit breakers' do
+      expect { BatchApi::Vroom::Client.new(circuit_breaker: { failure_threshold: 0 }) }.to raise_error(ArgumentError)
+      expect { BatchApi::Vroom::Client.new(circuit_breaker: { reset_timeout: -1 }) }.to raise_error(ArgumentError)
+    end
+
+    it 'returns what finished when the batch is cancelled from another thread' do
+      server.on('slow', delay: 5)
+      token = BatchApi::Vroom::CancellationToken.new
+      canceller = Thread.new { sleep 0.3 and token.cancel }
+
+      started = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+      responses = client.batch_send([{ 'body' => '{}' }, { 'body' => '{"slow":true}' }], cancellation_token: token)
+
+      expect(Process.clock_gettime(Process::CLOCK_MONOTONIC) - started).to be < 2
+      expect(responses.first['http_status_code']).to be 200
+      expect(responses.last['error_kind']).to eq('cancelled')
+      expect(token).to be_cancelled
+    ensure
+      canceller&.join
+    end
+
+    it 'cancels batches started with a cancelled token' do
+      server.on('{}', delay: 1)
+      token = BatchApi::Vroom::CancellationToken.new
+      token.cancel
+
+      responses = client.batch_send([{ 'body' => '{}' }] * 2, cancellation_token: token)
+      expect(responses.map { |r| r['error_kind'] }).to eq(%w[cancelled cancelled])
+    end
+
+    it 'raises argument errors for invalid rate limits' do
+      expect { BatchApi::Vroom::Client.new(rate_limit: { requests_per_second: 0 }) }.to raise_error(ArgumentError)
+      expect { BatchApi::Vroom::Client.new(rate_limit: { requests_per_second: 5, burst: 0 }) }.to raise_error(ArgumentError)
+      expect { BatchApi::Vroom::Client.new(rate_limit: { endpoints: { server.url => {} } }) }.to raise_error(ArgumentError)
+    end
+  end
+
+  describe BatchApi::Http do
+    let(:server) { BatchApi::Vroom::MockServer.start }
+
+    after { server.stop }
+
+    it 'sends each request with its own method, query and body, in order' do
+      requests = [
+        { 'url' => "#{server.url}/search?limit=1", 'query' => { 'q' => 'York', 'country' => :gb } },
+        { 'method' => :post, 'url' => "#{server.url}/reverse", 'body' => { lat: 53.96, lon: -1.08 } },
+        { 'method' => 'DELETE', 'url' => "#{server.url}/cache", 'headers' => { 'x-api-key' => 'secret' } }
+      ]
+
+      responses = BatchApi::Http.batch(requests, max_concurrency: 1)
+
+      expect(responses.map { |r| r['index'] }).to eq([0, 1, 2])
+      expect(responses.map { |r| r['http_status_code'] }).to eq([200, 200, 200])
+      expect(server.request_lines).to eq(['GET /search?limit=1&country=gb&q=York', 'POST /reverse', 'DELETE /cache'])
+      expect(server.requests).to eq(['', '{"lat":53.96,"lon":-1.08}', ''])
+      expect(server.request_headers[0]).not_to have_key('content-type')
+      expect(server.request_headers[1]['content-type']).to eq('application/json')
+      expect(server.request_headers[2]['x-api-key']).to eq('secret')
+    end
+
+    it 'joins paths onto the base url and retries like vroom batches' do
+      server.enqueue(status: 503)
+      response = BatchApi::Http.batch([{ 'url' => '/geocode' }], base_url: server.url, retry: { max_attempts: 2, base_delay: 0.01 }).first
+
+      expect(response).to include('http_status_code' => 200, 'attempts' => 2, 'endpoint' => server.url)
+      expect(server.request_lines).to eq(['GET /geocode'] * 2)
+    end
+
+    it "doesn't share cached responses between requests with different headers" do
+      requests = [
+        { 'url' => "#{server.url}/account", 'headers' => { 'Authorization' => 'Bearer one' } },
+        { 'url' => "#{server.url}/account", 'headers' => { 'Authorization' => 'Bearer two' } },
+        { 'url' => "#{server.url}/account", 'headers' => { 'authorization' => 'Bearer one' } }
+      ]
+
+      responses = BatchApi::Http.batch(requests, cache: { max_entries: 10, ttl: 60 })
+
+      expect(responses.map { |r| r['cache_hit'] }).to eq([false, false, true])
+      expect(server.request_headers.map { |headers| headers['authorization'] }).to contain_exactly('Bearer one', 'Bearer two')
+    end
+
+    it 'reports failures as error responses' do
+      response = BatchApi::Http.batch([{ 'url' => 'http://127.0.0.1:1/geocode' }]).first
+      expect(response['error_kind']).to eq('connect')
+    end
+
+    it "doesn't retry requests reqwest won't finish, like redirect loops" do
+      server.on('/loop', status: 302, headers: { 'Location' => "#{server.url}/loop" })
+      response = BatchApi::Http.batch([{ 'url' => "#{server.url}/loop" }], retry: { max_attempts: 3, base_delay: 0.01 }).first
+
+      expect(response).to include('error_kind' => 'request', 'attempts' => 1)
+    end
+
+    it 'raises argument errors for invalid requests' do
+      expect { BatchApi::Http.batch([{ 'method' => :get }]) }.to raise_error(ArgumentError)
+      expect { BatchApi::Http.batch([{ 'url' => '/geocode' }]) }.to raise_error(ArgumentError)
+      expect { BatchApi::Http.batch([{ 'method' => 'NOT A METHOD', 'url' => server.url }]) }.to raise_error(ArgumentError)
+      expect { BatchApi::Http.batch([{ 'url' => server.url }], parse_solutions: true) }.to raise_error(ArgumentError)
+    end
+  end
+
+  describe BatchApi::Osrm do
+    let(:server) { BatchApi::Vroom::MockServer.start }
+    let(:york) { [-1.08, 53.96] }
+    let(:leeds) { [-1.54, 53.8] }
+
+    after { server.stop }
+
+    it 'asks for tables and returns the parsed result' do
+      server.enqueue(body: { code: 'Ok', durations: [[0, 1800.5], [1790, 0]], distances: [[0, 40_000], [39_500, 0]] }.to_json)
+      request = { 'service' => :table, 'coordinates' => [york, leeds], 'annotations' => %w[duration distance] }
+
+      response = BatchApi::Osrm.batch([request], base_url: server.url).first
+
+      expect(server.request_lines).to eq(['GET /table/v1/driving/-1.08,53.96;-1.54,53.8?annotations=duration,distance'])
+      expect(response).to include('http_status_code' => 200, 'code' => 'Ok')
+      expect(response['result']['durations']).to eq([[0.0, 1800.5], [1790.0, 0.0]])
+    end
+
+    it 'asks for routes with their own profile and options' do
+      server.enqueue(body: { code: 'Ok', routes: [{ distance: 40_000, duration: 1800, legs: [] }], waypoints: [] }.to_json)
+      request = { 'service' => 'route', 'profile' => 'bike', 'coordinates' => [york, leeds], 'overview' => false, 'steps' => true }
+
+      response = BatchApi::Osrm::Client.new(base_url: server.url).batch([request]).first
+
+      expect(server.request_lines).to eq(['GET /route/v1/bike/-1.08,53.96;-1.54,53.8?steps=true&overview=false'])
+      expect(response['result']['routes'].first).to include('distance' => 40_000.0, 'duration' => 1800.0)
+    end
+
+    it 'sends lists of annotations for route, match and trip' do
+      requests = %i[route match trip].map do |service|
+        { 'service' => service, 'coordinates' => [york, leeds], 'annotations' => %w[duration speed] }
+      end
+      requests << { 'service' => :route, 'coordinates' => [york, leeds], 'annotations' => true }
+
+      BatchApi::Osrm.batch(requests, base_url: server.url, max_concurrency: 1)
+
+      expect(server.request_lines).to eq([
+        'GET /route/v1/driving/-1.08,53.96;-1.54,53.8?annotations=duration,speed',
+        'GET /match/v1/driving/-1.08,53.96;-1.54,53.8?annotations=duration,speed',
+        'GET /trip/v1/driving/-1.08,53.96;-1.54,53.8?annotations=duration,speed',
+        'GET /route/v1/driving/-1.08,53.96;-1.54,53.8?annotations=true'
+      ])
+      expect do
+        BatchApi::Osrm.batch([{ 'service' => :route, 'coordinates' => [york, leeds], 'annotations' => %w[colour] }], base_url: server.url)
+      end.to raise_error(ArgumentError, /annotations/)
+    end
+
+    it "reports osrm's error codes" do
+      server.on('/nearest/', status: 400, body: { code: 'NoSegment', message: 'Could not find a matching segment' }.to_json)
+
+      response = BatchApi::Osrm.batch([{ 'service' => :nearest, 'coordinates' => [york] }], base_url: server.url).first
+
+      expect(response).to include('code' => 'NoSegment', 'error_kind' => 'osrm')
+      expect(response['error_message']).to eq('NoSegment: Could not find a matching segment')
+      expect(response).not_to have_key('result')
+    end
+
+    it 'streams responses to a block' do
+      requests = [{ 'service' => :match, 'coordinates' => [york, leeds], 'timestamps' => [0, 1800] }] * 2
+      yielded = []
+
+      result = BatchApi::Osrm.batch(requests, base_url: server.url) { |index, response| yielded << [index, response['error_kind']] }
+
+      expect(result).to be_nil
+      expect(yielded.sort).to eq([[0, 'invalid_response'], [1, 'invalid_response']])
+    end
+
+    it 'raises argument errors for invalid requests' do
+      expect { BatchApi::Osrm.batch([{ 'coordinates' => [york] }], base_url: server.url) }.to raise_error(ArgumentError)
+      expect { BatchApi::Osrm.batch([{ 'service' => :isochrone, 'coordinates' => [york] }], base_url: server.url) }.to raise_error(ArgumentError)
+      expect { BatchApi::Osrm.batch([{ 'service' => :route, 'coordinates' => [york] }], base_url: server.url) }.to raise_error(ArgumentError)
+      expect { BatchApi::Osrm.batch([{ 'service' => :table, 'coordinates' => [york], 'sources' => [1] }], base_url: server.url) }.to raise_error(ArgumentError)
+      expect { BatchApi::Osrm.batch([{ 'service' => :route, 'coordinates' => [york, leeds], 'geometries' => :wkt }], base_url: server.url) }.to raise_error(ArgumentError)
+      expect { BatchApi::Osrm.batch([{ 'service' => :table, 'coordinates' => [york] }], base_url: server.url, parse_solutions: true) }.to raise_error(ArgumentError)
+    end
+
+    describe '#add_matrices' do
+      let(:osrm) { BatchApi::Osrm::Client.new(base_url: server.url) }
+      let(:table) { { code: 'Ok', durations: [[0, 1790.4], [1800.5, 0]], distances: [[0, 39_500], [40_000, 0]] }.to_json }
+
+      def problem(*stops, profile: nil)
+        BatchApi::Vroom::Problem.new.tap do |p|
+          p.add_vehicle({ id: 1, start: stops.first, profile: profile }.compact)
+          stops.drop(1).each_with_index { |stop, i| p.add_job(id: i + 1, location: stop) }
+        end
+      end
+
+      it 'rewrites problems to use location indices into matrices from osrm' do
+        server.enqueue(body: table)
+        york_to_leeds = problem(york, leeds)
+
+        expect(osrm.add_matrices([york_to_leeds])).to eq([york_to_leeds])
+
+        # locations are in coordinate order, so leeds comes first
+        expect(server.request_lines).to eq(['GET /table/v1/driving/-1.54,53.8;-1.08,53.96?annotations=duration,distance'])
+        json = JSON.parse(york_to_leeds.to_json)
+        expect(json['vehicles'].first).to include('start' => york, 'start_index' => 1)
+        expect(json['jobs'].first).to include('location' => leeds, 'location_index' => 0)
+        expect(json['matrices']).to eq('car' => { 'durations' => [[0, 1790], [1801, 0]], 'distances' => [[0, 39_500], [40_000, 0]] })
+      end
+
+      it 'raises for locations with an index but no coordinates' do
+        depot_return = BatchApi::Vroom::Problem.new.tap do |p|
+          p.add_vehicle(id: 1, start: york, end_index: 1)
+          p.add_job(id: 1, location: leeds)
+        end
+
+        expect { osrm.add_matrices([depot_return]) }.to raise_error(ArgumentError, /vehicle 1 end needs coordinates/)
+        expect(server.request_lines).to be_empty
+      end
+
+      it 'reuses matrices for problems with the same stops' do
+        server.enqueue(body: table)
+
+        osrm.add_matrices([problem(york, leeds), problem(york, leeds, leeds)])
+        leeds_to_york = osrm.add_matrices([problem(leeds, york)]).first
+
+        expect(server.request_lines.size).to eq(1)
+        expect(JSON.parse(leeds_to_york.to_json)['vehicles'].first['start_index']).to eq(0)
+      end
+
+      it 'rewrites json problems and maps vroom profiles to osrm ones' do
+        server.enqueue(body: table)
+
+        json = osrm.add_matrices([problem(york, leeds, profile: 'bike').to_json], profiles: { bike: 'cycling' }).first
+
+        expect(server.request_lines.first).to start_with('GET /table/v1/cycling/')
+        expect(JSON.parse(json)['matrices'].keys).to eq(['bike'])
+      end
+
+      it 'keeps the fields of json problems it does not know about' do
+        server.enqueue(body: table)
+        json = {
+          vehicles: [{ id: 1, start: york, type: 'van', steps: [{ type: 'start' }] }],
+          jobs: [{ id: 1, location: leeds, amount: [1] }],
+          options: { g: true, c: true }
+        }.to_json
+
+        rewritten = JSON.parse(osrm.add_matrices([json]).first)
+
+        expect(rewritten['vehicles'].first).to include('type' => 'van', 'steps' => [{ 'type' => 'start' }], 'start_index' => 1)
+        expect(rewritten['jobs'].first).to include('amount' => [1], 'location_index' => 0)
+        expect(rewritten['options']).to eq('g' => true, 'c' => true)
+        expect(rewritten['matrices'].keys).to eq(['car'])
+      end
+
+      it 'splits big tables into tiles of at most max_table_size' do
+        scarborough = [-0.5, 54.0]
+        tile = ->(cells) { { code: 'Ok', durations: cells, distances: cells }.to_json }
+        server.enqueue(body: tile.([[0, 10], [11, 0]]))
+        server.enqueue(body: tile.([[20], [21]]))
+        server.enqueue(body: tile.([[30, 31]]))
+        server.enqueue(body: tile.([[0]]))
+
+        big = osrm.add_matrices([problem(leeds, york, scarborough)], max_table_size: 2, max_concurrency: 1).first
+
+        expect(server.request_lines).to eq([
+          'GET /table/v1/driving/-1.54,53.8;-1.08,53.96?annotations=duration,distance',
+          'GET /table/v1/driving/-1.54,53.8;-1.08,53.96;-0.5,54?sources=0;1&destinations=2&annotations=duration,distance',
+          'GET /table/v1/driving/-0.5,54;-1.54,53.8;-1.08,53.96?sources=0&destinations=1;2&annotations=duration,distance',
+          'GET /table/v1/driving/-0.5,54?annotations=duration,distance'
+        ])
+        expect(JSON.parse(big.to_json)['matrices']['car']['durations']).to eq([[0, 10, 20], [11, 0, 21], [30, 31, 0]])
+      end
+
+      it 'raises when osrm cannot give a table' do
+        server.enqueue(body: { code: 'Ok', durations: [[0, nil], [nil, 0]], distances: [[0, nil], [nil, 0]] }.to_json)
+        expect { osrm.add_matrices([problem(york, leeds)]) }.to raise_error(BatchApi::Osrm::Error, /no route/)
+
+        server.enqueue(body: { code: 'TooBig', message: 'Too many table coordinates' }.to_json, status: 400)
+        expect { osrm.add_matrices([problem(york, leeds)]) }.to raise_error(BatchApi::Osrm::Error, /TooBig/)
+      end
+
+      it 'raises argument errors for problems it cannot rewrite' do
+        expect { osrm.add_matrices([BatchApi::Vroom::Problem.new]) }.to raise_error(ArgumentError)
+        expect { osrm.add_matrices(['not json']) }.to raise_error(ArgumentError)
+        expect { osrm.add_matrices([problem(york, leeds)], max_table_size: 0) }.to raise_error(ArgumentError)
+      end
+    end
+  end
+
   describe BatchApi::Vroom do
     describe '#batch_send_api_requests' do
       context 'incorrectly formatted argument' do
@@ -69,6 +994,126 @@ RSpec.describe BatchApi do
           expect(BatchApi::Vroom.batch_send_api_requests([])).to eq([])
         end
       end
+
+      context 'with a url key per request' do
+        around do |example|
+          vroom_url = ENV.delete('VROOM_URL')
+          example.run
+        ensure
+          ENV['VROOM_URL'] = vroom_url
+        end
+
+        it 'sends full urls as they are without needing VROOM_URL' do
+          responses = BatchApi::Vroom.batch_send_api_requests([{ 'url' => 'http://127.0.0.1:1', 'body' => '{}' }])
+          expect(responses.first['error_kind']).to eq('connect')
+        end
+
+        it 'joins paths onto the base url' do
+          requests = [{ 'url' => '/uk', 'body' => '{}' }, { 'url' => 'us', 'body' => '{}' }]
+          responses = BatchApi::Vroom.batch_send_api_requests(requests, base_url: 'http://127.0.0.1:1/')
+          expect(responses.map { |r| r['error_kind'] }).to eq(%w[connect connect])
+        end
+
+        it 'raises argument errors for paths without a base url' do
+          expect do
+            BatchApi::Vroom.batch_send_api_requests([{ 'url' => '/uk', 'body' => '{}' }])
+          end.to raise_error(ArgumentError)
+        end
+
+        it 'raises argument errors for invalid urls' do
+          expect do
+            BatchApi::Vroom.batch_send_api_requests([{ 'url' => 'uk', 'body' => '{}' }], base_url: 'not a url')
+          end.to raise_error(ArgumentError)
+        end
+      end
+
+      context 'when vroom cannot be reached' do
+        around do |example|
+          vroom_url = ENV['VROOM_URL']
+          # nothing listens on port 1 so the connection is refused
+          ENV['VROOM_URL'] = 'http://127.0.0.1:1'
+          example.run
+        ensure
+          ENV['VROOM_URL'] = vroom_url
+        end
+
+        it 'returns an error entry for each request instead of raising' do
+          responses = BatchApi::Vroom.batch_send_api_requests([{ 'body' => '{}' }] * 2)
+          expect(responses.size).to be 2
+          responses.each do |response|
+            expect(response['error_kind']).to eq('connect')
+            expect(response['error_message']).not_to be_empty
+          end
+        end
+
+        it 'retries connection errors up to max_attempts' do
+          options = { retry: { max_attempts: 3, base_delay: 0.01 } }
+          responses = BatchApi::Vroom.batch_send_api_requests([{ 'body' => '{}' }], options)
+          expect(responses.first['error_kind']).to eq('connect')
+          expect(responses.first['attempts']).to eq(3)
+        end
+      end
+
+      context 'while the batch is waiting on the network' do
+        # accepts connections into the backlog but never answers them
+        let(:server) { TCPServer.new('127.0.0.1', 0) }
+
+        around do |example|
+          vroom_url = ENV['VROOM_URL']
+          ENV['VROOM_URL'] = "http://127.0.0.1:#{server.addr[1]}"
+          example.run
+        ensure
+          ENV['VROOM_URL'] = vroom_url
+          server.close
+        end
+
+        it 'lets other ruby threads run' do
+          ticks = 0
+          ticker = Thread.new { loop { ticks += 1; sleep 0.01 } }
+          responses = BatchApi::Vroom.batch_send_api_requests([{ 'body' => '{}' }], request_timeout: 0.5)
+          ticker.kill
+
+          expect(responses.first['error_kind']).to eq('timeout')
+          expect(ticks).to be > 10
+        end
+
+        it 'can be interrupted with Thread#raise' do
+          batch = Thread.new { BatchApi::Vroom.batch_send_api_requests([{ 'body' => '{}' }]) }
+          sleep 0.2
+          batch.raise(Interrupt)
+
+          expect { batch.join(5) }.to raise_error(Interrupt)
+        end
+      end
+
+      context 'with an options hash' do
+        it 'accepts max_concurrency' do
+          expect(BatchApi::Vroom.batch_send_api_requests([], max_concurrency: 2)).to eq([])
+          expect(BatchApi::Vroom.batch_send_api_requests([], { 'max_concurrency' => 2 })).to eq([])
+        end
+
+        it 'raises argument errors for a max_concurrency of zero' do
+          expect { BatchApi::Vroom.batch_send_api_requests([], max_concurrency: 0) }.to raise_error(ArgumentError)
+        end
+
+        it 'accepts timeouts in seconds' do
+          options = { connect_timeout: 1, request_timeout: 2.5, batch_timeout: 10 }
+          expect(BatchApi::Vroom.batch_send_api_requests([], options)).to eq([])
+        end
+
+        it 'raises argument errors for invalid retry policies' do
+          expect { BatchApi::Vroom.batch_send_api_requests([], retry: { max_attempts: 0 }) }.to raise_error(ArgumentError)
+          expect { BatchApi::Vroom.batch_send_api_requests([], retry: { jitter: 1.5 }) }.to raise_error(ArgumentError)
+          expect { BatchApi::Vroom.batch_send_api_requests([], retry: { base_delay: 0 }) }.to raise_error(ArgumentError)
+        end
+
+        it 'raises argument errors for timeouts that are not positive' do
+          %i[connect_timeout request_timeout batch_timeout].each do |timeout|
+            expect { BatchApi::Vroom.batch_send_api_requests([], timeout => 0) }.to raise_error(ArgumentError)
+            expect { BatchApi::Vroom.batch_send_api_requests([], timeout => -1) }.to raise_error(ArgumentError)
+          end
+        end
+      end
     end
   end
 end